@@ -1,60 +1,224 @@
 use anyhow::{Context, Result};
 use lsp_types::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::path::Path;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{Child, Command};
-use tokio::sync::{oneshot, Mutex};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::config::{RootPolicy, ServerConfig};
+use crate::diagnostics::DiagnosticsStore;
+use crate::protocol::{
+    classify_message, encode_message, InboundMessage, JsonRpcError, JsonRpcNotification,
+    JsonRpcReply, JsonRpcRequest, JsonRpcResponse,
+};
+use crate::server_log::ServerLog;
+use crate::text;
+
+tokio::task_local! {
+    /// Per-call timeout set around a single MCP tool call
+    static TIMEOUT_OVERRIDE: Duration;
+}
 
-use crate::config::ServerConfig;
-use crate::protocol::{encode_message, JsonRpcNotification, JsonRpcRequest, JsonRpcResponse};
+/// Run `f` with every LSP request it makes bounded by `timeout` instead of
+/// the configured per-server / per-method timeout
+pub async fn with_timeout<F: Future>(timeout: Option<Duration>, f: F) -> F::Output {
+    match timeout {
+        Some(timeout) => TIMEOUT_OVERRIDE.scope(timeout, f).await,
+        None => f.await,
+    }
+}
 
 pub struct LspClient {
     name: String,
-    process: Mutex<Option<Child>>,
-    stdin: Mutex<Option<tokio::process::ChildStdin>>,
+    config: ServerConfig,
+    /// Set while the process is alive; cleared by the exit watcher
+    alive: Arc<AtomicBool>,
+    /// How the process exited, for error messages after a crash
+    exit_status: Arc<std::sync::Mutex<Option<String>>>,
+    /// Asks the exit watcher to kill the process
+    kill: Mutex<Option<oneshot::Sender<()>>>,
+    watcher: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    writer: Mutex<Option<mpsc::UnboundedSender<String>>>,
     pending: Arc<Mutex<HashMap<i64, oneshot::Sender<JsonRpcResponse>>>>,
     next_id: AtomicI64,
     initialized: Mutex<bool>,
-    root_uri: Mutex<Option<Uri>>,
+    /// Project root this instance serves
+    root: PathBuf,
+    /// Further project roots this instance serves via workspace folders
+    extra_folders: std::sync::Mutex<Vec<PathBuf>>,
+    /// Held while a `workspace/executeCommand` runs
+    command_lock: Mutex<()>,
+    /// Documents we've sent `didOpen` for; also reopened after a restart
+    documents: Mutex<HashMap<PathBuf, OpenDocument>>,
+    /// Last version of each document we closed. Reopening continues from
+    /// there, so diagnostics cached for the old version can't outrank the new.
+    closed_versions: std::sync::Mutex<HashMap<PathBuf, i32>>,
+    last_used: std::sync::Mutex<Instant>,
+    context: Arc<HandlerContext>,
+    server_capabilities: Mutex<Option<ServerCapabilities>>,
+    diagnostics: Arc<DiagnosticsStore>,
+    stderr: Arc<ServerLog>,
 }
 
 impl LspClient {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, config: ServerConfig, root: PathBuf) -> Self {
+        let stderr = Arc::new(ServerLog::new(config.stderr_lines));
+        Self::with_log(name, config, root, stderr)
+    }
+
+    /// Like `new`, but appending stderr to an existing log (e.g. the one
+    /// from the instance that crashed before a restart)
+    pub fn with_log(name: &str, config: ServerConfig, root: PathBuf, stderr: Arc<ServerLog>) -> Self {
         Self {
             name: name.to_string(),
-            process: Mutex::new(None),
-            stdin: Mutex::new(None),
+            alive: Arc::new(AtomicBool::new(false)),
+            exit_status: Arc::new(std::sync::Mutex::new(None)),
+            kill: Mutex::new(None),
+            watcher: Mutex::new(None),
+            writer: Mutex::new(None),
             pending: Arc::new(Mutex::new(HashMap::new())),
             next_id: AtomicI64::new(1),
             initialized: Mutex::new(false),
-            root_uri: Mutex::new(None),
+            root,
+            extra_folders: std::sync::Mutex::new(Vec::new()),
+            command_lock: Mutex::new(()),
+            documents: Mutex::new(HashMap::new()),
+            closed_versions: std::sync::Mutex::new(HashMap::new()),
+            last_used: std::sync::Mutex::new(Instant::now()),
+            context: Arc::new(HandlerContext {
+                settings: config.settings.clone(),
+                workspace_folders: Default::default(),
+                command_edits: Default::default(),
+            }),
+            server_capabilities: Mutex::new(None),
+            diagnostics: Arc::new(DiagnosticsStore::new()),
+            stderr,
+            config,
         }
     }
 
-    pub async fn start(&self, config: &ServerConfig) -> Result<()> {
-        let mut cmd = Command::new(&config.command);
-        cmd.args(&config.args)
+    pub async fn start(&self) -> Result<()> {
+        let mut cmd = Command::new(&self.config.command);
+        cmd.args(&self.config.args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null());
-
-        let mut child = cmd.spawn().context("Failed to spawn LSP process")?;
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = cmd.spawn().with_context(|| {
+            format!(
+                "Failed to spawn LSP process for {}{}",
+                self.name,
+                self.stderr_tail_note()
+            )
+        })?;
 
-        let stdin = child.stdin.take().unwrap();
+        let mut stdin = child.stdin.take().unwrap();
         let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        // Stderr task: keep the tail in the ring buffer and forward to tracing
+        let level = match self.config.stderr_log_level.as_str() {
+            "off" => None,
+            level => Some(level.parse().unwrap_or(tracing::Level::DEBUG)),
+        };
+        let log = self.stderr.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            // Bytes, not lines: a non-UTF-8 line must not stop us draining the
+            // pipe, or the server blocks once it fills up
+            let mut stderr = BufReader::new(stderr);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match stderr.read_until(b'\n', &mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\n', '\r']);
+                match level {
+                    Some(tracing::Level::ERROR) => tracing::error!(server = %name, "{}", line),
+                    Some(tracing::Level::WARN) => tracing::warn!(server = %name, "{}", line),
+                    Some(tracing::Level::INFO) => tracing::info!(server = %name, "{}", line),
+                    Some(tracing::Level::DEBUG) => tracing::debug!(server = %name, "{}", line),
+                    Some(tracing::Level::TRACE) => tracing::trace!(server = %name, "{}", line),
+                    None => {}
+                }
+                log.push(line);
+            }
+        });
+
+        // Writer task: both we and the reader task (replying to server
+        // requests) send through this channel. Since the reader keeps its
+        // sender, the channel never closes on its own; an empty message
+        // (never a valid encoded one) ends the task, closing stdin.
+        let (writer, mut outgoing) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(msg) = outgoing.recv().await {
+                if msg.is_empty() {
+                    break;
+                }
+                if stdin.write_all(msg.as_bytes()).await.is_err() || stdin.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        *self.writer.lock().await = Some(writer.clone());
+        self.alive.store(true, Ordering::SeqCst);
 
-        *self.process.lock().await = Some(child);
-        *self.stdin.lock().await = Some(stdin);
+        // Exit watcher: owns the child, notices crashes and fails everything
+        // still waiting on a response instead of letting it run into the timeout
+        let (kill, killed) = oneshot::channel::<()>();
+        let alive = self.alive.clone();
+        let exit_status = self.exit_status.clone();
+        let pending = self.pending.clone();
+        let name = self.name.clone();
+        let watcher = tokio::spawn(async move {
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = killed => {
+                    match tokio::time::timeout(Duration::from_secs(2), child.wait()).await {
+                        Ok(status) => status,
+                        Err(_) => {
+                            let _ = child.kill().await;
+                            child.wait().await
+                        }
+                    }
+                }
+            };
+
+            alive.store(false, Ordering::SeqCst);
+            let status = match status {
+                Ok(s) => s.to_string(),
+                Err(e) => e.to_string(),
+            };
+            eprintln!("[lsp-mcp-rs] {} exited: {}", name, status);
+            *exit_status.lock().unwrap() = Some(status);
+            // Dropping the senders wakes every waiting request with an error
+            pending.lock().await.clear();
+        });
+        *self.kill.lock().await = Some(kill);
+        *self.watcher.lock().await = Some(watcher);
 
         let pending = self.pending.clone();
+        let diagnostics = self.diagnostics.clone();
+        let context = self.context.clone();
 
         // Spawn reader task
         tokio::spawn(async move {
+            let handlers = request_handlers();
             let mut reader = BufReader::new(stdout);
 
             loop {
@@ -87,14 +251,43 @@ impl LspClient {
                     return;
                 }
 
-                let Ok(response) = serde_json::from_slice::<JsonRpcResponse>(&body) else {
+                let Ok(message) = classify_message(&body) else {
                     continue;
                 };
 
-                if let Some(id) = response.id {
-                    let mut pending = pending.lock().await;
-                    if let Some(tx) = pending.remove(&id) {
-                        let _ = tx.send(response);
+                match message {
+                    InboundMessage::Response(response) => {
+                        if let Some(id) = response.id {
+                            let mut pending = pending.lock().await;
+                            if let Some(tx) = pending.remove(&id) {
+                                let _ = tx.send(response);
+                            }
+                        }
+                    }
+                    InboundMessage::Notification(notification) => {
+                        handle_notification(&diagnostics, notification).await;
+                    }
+                    // Answered once the edit has been written, which must not
+                    // hold up the responses the command is waiting on
+                    InboundMessage::Request(request) if request.method == "workspace/applyEdit" => {
+                        let context = context.clone();
+                        let writer = writer.clone();
+                        tokio::spawn(async move {
+                            let outcome = handle_apply_edit(&context, request.params).await;
+                            let reply = JsonRpcReply::new(request.id, outcome);
+                            let _ = writer.send(encode_message(&reply));
+                        });
+                    }
+                    InboundMessage::Request(request) => {
+                        let outcome = match handlers.get(request.method.as_str()) {
+                            Some(handler) => handler(&context, request.params),
+                            None => Err(JsonRpcError::new(
+                                JsonRpcError::METHOD_NOT_FOUND,
+                                format!("Unhandled method: {}", request.method),
+                            )),
+                        };
+                        let reply = JsonRpcReply::new(request.id, outcome);
+                        let _ = writer.send(encode_message(&reply));
                     }
                 }
             }
@@ -103,8 +296,136 @@ impl LspClient {
         Ok(())
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub async fn is_running(&self) -> bool {
-        self.process.lock().await.is_some()
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    pub fn stderr_tail(&self, n: usize) -> Vec<String> {
+        self.stderr.tail(n)
+    }
+
+    pub fn stderr_log(&self) -> Arc<ServerLog> {
+        self.stderr.clone()
+    }
+
+    /// Recent stderr output, formatted for appending to an error message
+    fn stderr_tail_note(&self) -> String {
+        let tail = self.stderr.tail(20);
+        if tail.is_empty() {
+            return String::new();
+        }
+        format!("\nRecent stderr from {}:\n  {}", self.name, tail.join("\n  "))
+    }
+
+    /// Why the process is gone, if it is
+    fn exit_reason(&self) -> Option<String> {
+        if self.alive.load(Ordering::SeqCst) {
+            return None;
+        }
+        let status = self.exit_status.lock().unwrap().clone()?;
+        Some(format!("{} exited ({})", self.name, status))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Project roots served as extra workspace folders, besides `root`
+    pub fn workspace_folders(&self) -> Vec<PathBuf> {
+        self.extra_folders.lock().unwrap().clone()
+    }
+
+    /// Whether the server takes `workspace/didChangeWorkspaceFolders`, so
+    /// this instance can serve more than one project root
+    pub async fn supports_workspace_folders(&self) -> bool {
+        let capabilities = self.server_capabilities.lock().await;
+        let Some(folders) = capabilities
+            .as_ref()
+            .and_then(|c| c.workspace.as_ref())
+            .and_then(|w| w.workspace_folders.as_ref())
+        else {
+            return false;
+        };
+        folders.supported == Some(true)
+            && matches!(
+                folders.change_notifications,
+                Some(OneOf::Left(true)) | Some(OneOf::Right(_))
+            )
+    }
+
+    /// Start serving `path` as an additional workspace folder
+    pub async fn add_workspace_folder(&self, path: &Path) -> Result<()> {
+        let mut folders = self.workspace_folders();
+        folders.push(path.to_path_buf());
+        self.set_workspace_folders(&folders).await
+    }
+
+    /// Serve exactly `folders` (plus `root`, which is always kept). Before
+    /// `initialize` this only decides what gets sent there; afterwards the
+    /// difference goes out as `workspace/didChangeWorkspaceFolders`.
+    pub async fn set_workspace_folders(&self, folders: &[PathBuf]) -> Result<()> {
+        // Held so initialization can't snapshot the folders halfway through
+        let initialized = self.initialized.lock().await;
+
+        let mut wanted: Vec<PathBuf> = Vec::new();
+        for folder in folders {
+            if *folder != self.root && !wanted.contains(folder) {
+                wanted.push(folder.clone());
+            }
+        }
+        let current = std::mem::replace(&mut *self.extra_folders.lock().unwrap(), wanted.clone());
+        if !*initialized {
+            return Ok(());
+        }
+
+        let added: Vec<_> = wanted.iter().filter(|f| !current.contains(f)).collect();
+        let removed: Vec<_> = current.iter().filter(|f| !wanted.contains(f)).collect();
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+
+        *self.context.workspace_folders.write().unwrap() = self.all_workspace_folders()?;
+        let params = DidChangeWorkspaceFoldersParams {
+            event: WorkspaceFoldersChangeEvent {
+                added: added.into_iter().map(|f| workspace_folder(f)).collect::<Result<_>>()?,
+                removed: removed.into_iter().map(|f| workspace_folder(f)).collect::<Result<_>>()?,
+            },
+        };
+        self.send_notification("workspace/didChangeWorkspaceFolders", Some(serde_json::to_value(params)?))
+            .await
+    }
+
+    /// `root` followed by the extra folders, as sent to the server
+    fn all_workspace_folders(&self) -> Result<Vec<WorkspaceFolder>> {
+        std::iter::once(&self.root)
+            .chain(self.extra_folders.lock().unwrap().iter())
+            .map(|f| workspace_folder(f))
+            .collect()
+    }
+
+    pub async fn is_initialized(&self) -> bool {
+        *self.initialized.lock().await
+    }
+
+    /// Whether nothing is waiting on this server right now
+    pub async fn is_idle(&self) -> bool {
+        self.pending.lock().await.is_empty()
+    }
+
+    pub fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    pub fn last_used(&self) -> Instant {
+        *self.last_used.lock().unwrap()
+    }
+
+    pub async fn open_documents(&self) -> Vec<PathBuf> {
+        self.documents.lock().await.keys().cloned().collect()
     }
 
     async fn send_request(&self, method: &str, params: Option<Value>) -> Result<JsonRpcResponse> {
@@ -115,53 +436,257 @@ impl LspClient {
         let (tx, rx) = oneshot::channel();
         self.pending.lock().await.insert(id, tx);
 
-        {
-            let mut stdin = self.stdin.lock().await;
-            if let Some(ref mut stdin) = *stdin {
-                stdin.write_all(msg.as_bytes()).await?;
-                stdin.flush().await?;
-            } else {
-                anyhow::bail!("LSP not running");
+        let writer = match self.writer().await {
+            Ok(writer) if self.is_running().await && writer.send(msg).is_ok() => writer,
+            _ => {
+                self.pending.lock().await.remove(&id);
+                anyhow::bail!(self.exit_reason().unwrap_or_else(|| "LSP not running".into()));
+            }
+        };
+
+        // From here on, a timeout or the caller dropping this future
+        // (MCP cancellation) cancels the request on the server
+        let mut guard = CancelOnDrop {
+            id,
+            pending: self.pending.clone(),
+            writer,
+            armed: true,
+        };
+
+        let timeout = self.request_timeout(method);
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(response) => {
+                guard.armed = false;
+                response.map_err(|_| {
+                    anyhow::anyhow!(self
+                        .exit_reason()
+                        .unwrap_or_else(|| "LSP response channel closed".into()))
+                })
             }
+            Err(_) => anyhow::bail!("LSP request {} timed out after {}ms", method, timeout.as_millis()),
         }
+    }
 
-        tokio::time::timeout(std::time::Duration::from_secs(30), rx)
-            .await
-            .context("LSP request timed out")?
-            .context("LSP response channel closed")
+    fn request_timeout(&self, method: &str) -> Duration {
+        // A cold server start shouldn't fail just because a tool call asked for a short timeout
+        if method != "initialize" {
+            if let Ok(timeout) = TIMEOUT_OVERRIDE.try_with(|t| *t) {
+                return timeout;
+            }
+        }
+        let ms = self
+            .config
+            .method_timeouts
+            .get(method)
+            .copied()
+            .unwrap_or(self.config.timeout_ms);
+        Duration::from_millis(ms)
+    }
+
+    /// Send a request and parse its result, turning an error response into `Err`
+    async fn request<P: Serialize, R: DeserializeOwned>(&self, method: &str, params: P) -> Result<R> {
+        let response = self
+            .send_request(method, Some(serde_json::to_value(params)?))
+            .await?;
+
+        if let Some(error) = response.error {
+            anyhow::bail!("{} failed: {}", method, error.message);
+        }
+        Ok(serde_json::from_value(response.result.unwrap_or(Value::Null))?)
     }
 
     async fn send_notification(&self, method: &str, params: Option<Value>) -> Result<()> {
         let notification = JsonRpcNotification::new(method, params);
         let msg = encode_message(&notification);
 
-        let mut stdin = self.stdin.lock().await;
-        if let Some(ref mut stdin) = *stdin {
-            stdin.write_all(msg.as_bytes()).await?;
-            stdin.flush().await?;
+        // Notifications to a server that isn't running are simply dropped
+        if let Ok(writer) = self.writer().await {
+            let _ = writer.send(msg);
         }
         Ok(())
     }
 
-    pub async fn initialize(&self, root_path: &Path) -> Result<()> {
+    async fn writer(&self) -> Result<mpsc::UnboundedSender<String>> {
+        self.writer
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("LSP not running"))
+    }
+
+    pub async fn ensure_initialized(&self) -> Result<()> {
+        // Held throughout, so concurrent tool calls don't initialize twice
+        let mut initialized = self.initialized.lock().await;
+        if *initialized {
+            return Ok(());
+        }
+
+        let root_path = self.root.as_path();
         let root_uri = path_to_uri(root_path)?;
 
-        *self.root_uri.lock().await = Some(root_uri.clone());
+        let folders = self.all_workspace_folders()?;
+        *self.context.workspace_folders.write().unwrap() = folders.clone();
 
         let params = InitializeParams {
             process_id: Some(std::process::id()),
             #[allow(deprecated)]
             root_uri: Some(root_uri.clone()),
+            workspace_folders: Some(folders),
+            initialization_options: self.config.initialization_options.clone(),
             capabilities: ClientCapabilities {
                 text_document: Some(TextDocumentClientCapabilities {
                     hover: Some(HoverClientCapabilities::default()),
+                    signature_help: Some(SignatureHelpClientCapabilities {
+                        signature_information: Some(SignatureInformationSettings {
+                            documentation_format: Some(vec![MarkupKind::Markdown, MarkupKind::PlainText]),
+                            parameter_information: Some(ParameterInformationSettings {
+                                label_offset_support: Some(true),
+                            }),
+                            active_parameter_support: Some(true),
+                        }),
+                        ..Default::default()
+                    }),
                     definition: Some(GotoCapability::default()),
+                    type_definition: Some(GotoCapability::default()),
+                    implementation: Some(GotoCapability::default()),
+                    declaration: Some(GotoCapability::default()),
+                    call_hierarchy: Some(CallHierarchyClientCapabilities::default()),
+                    type_hierarchy: Some(TypeHierarchyClientCapabilities::default()),
+                    inlay_hint: Some(InlayHintClientCapabilities::default()),
+                    semantic_tokens: Some(SemanticTokensClientCapabilities {
+                        requests: SemanticTokensClientCapabilitiesRequests {
+                            range: Some(true),
+                            full: Some(SemanticTokensFullOptions::Bool(true)),
+                        },
+                        token_types: vec![
+                            SemanticTokenType::NAMESPACE,
+                            SemanticTokenType::TYPE,
+                            SemanticTokenType::CLASS,
+                            SemanticTokenType::ENUM,
+                            SemanticTokenType::INTERFACE,
+                            SemanticTokenType::STRUCT,
+                            SemanticTokenType::TYPE_PARAMETER,
+                            SemanticTokenType::PARAMETER,
+                            SemanticTokenType::VARIABLE,
+                            SemanticTokenType::PROPERTY,
+                            SemanticTokenType::ENUM_MEMBER,
+                            SemanticTokenType::EVENT,
+                            SemanticTokenType::FUNCTION,
+                            SemanticTokenType::METHOD,
+                            SemanticTokenType::MACRO,
+                            SemanticTokenType::KEYWORD,
+                            SemanticTokenType::MODIFIER,
+                            SemanticTokenType::COMMENT,
+                            SemanticTokenType::STRING,
+                            SemanticTokenType::NUMBER,
+                            SemanticTokenType::REGEXP,
+                            SemanticTokenType::OPERATOR,
+                            SemanticTokenType::DECORATOR,
+                        ],
+                        token_modifiers: vec![
+                            SemanticTokenModifier::DECLARATION,
+                            SemanticTokenModifier::DEFINITION,
+                            SemanticTokenModifier::READONLY,
+                            SemanticTokenModifier::STATIC,
+                            SemanticTokenModifier::DEPRECATED,
+                            SemanticTokenModifier::ABSTRACT,
+                            SemanticTokenModifier::ASYNC,
+                            SemanticTokenModifier::MODIFICATION,
+                            SemanticTokenModifier::DOCUMENTATION,
+                            SemanticTokenModifier::DEFAULT_LIBRARY,
+                        ],
+                        formats: vec![TokenFormat::RELATIVE],
+                        ..Default::default()
+                    }),
                     references: Some(ReferenceClientCapabilities::default()),
+                    document_highlight: Some(DocumentHighlightClientCapabilities::default()),
+                    folding_range: Some(FoldingRangeClientCapabilities {
+                        folding_range: Some(FoldingRangeCapability {
+                            collapsed_text: Some(true),
+                        }),
+                        ..Default::default()
+                    }),
+                    selection_range: Some(SelectionRangeClientCapabilities::default()),
                     document_symbol: Some(DocumentSymbolClientCapabilities::default()),
-                    completion: Some(CompletionClientCapabilities::default()),
-                    rename: Some(RenameClientCapabilities::default()),
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            documentation_format: Some(vec![MarkupKind::Markdown, MarkupKind::PlainText]),
+                            resolve_support: Some(CompletionItemCapabilityResolveSupport {
+                                properties: vec!["documentation".into(), "detail".into()],
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    rename: Some(RenameClientCapabilities {
+                        prepare_support: Some(true),
+                        ..Default::default()
+                    }),
                     formatting: Some(DocumentFormattingClientCapabilities::default()),
+                    range_formatting: Some(DocumentRangeFormattingClientCapabilities::default()),
+                    code_action: Some(CodeActionClientCapabilities {
+                        code_action_literal_support: Some(CodeActionLiteralSupport {
+                            code_action_kind: CodeActionKindLiteralSupport {
+                                value_set: [
+                                    CodeActionKind::EMPTY,
+                                    CodeActionKind::QUICKFIX,
+                                    CodeActionKind::REFACTOR,
+                                    CodeActionKind::REFACTOR_EXTRACT,
+                                    CodeActionKind::REFACTOR_INLINE,
+                                    CodeActionKind::REFACTOR_REWRITE,
+                                    CodeActionKind::SOURCE,
+                                    CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+                                    CodeActionKind::SOURCE_FIX_ALL,
+                                ]
+                                .into_iter()
+                                .map(|k| k.as_str().to_string())
+                                .collect(),
+                            },
+                        }),
+                        is_preferred_support: Some(true),
+                        disabled_support: Some(true),
+                        data_support: Some(true),
+                        resolve_support: Some(CodeActionCapabilityResolveSupport {
+                            properties: vec!["edit".into()],
+                        }),
+                        ..Default::default()
+                    }),
                     publish_diagnostics: Some(PublishDiagnosticsClientCapabilities::default()),
+                    diagnostic: Some(DiagnosticClientCapabilities {
+                        dynamic_registration: Some(false),
+                        related_document_support: Some(true),
+                    }),
+                    ..Default::default()
+                }),
+                workspace: Some(WorkspaceClientCapabilities {
+                    configuration: Some(true),
+                    workspace_folders: Some(true),
+                    apply_edit: Some(true),
+                    symbol: Some(WorkspaceSymbolClientCapabilities {
+                        resolve_support: Some(WorkspaceSymbolResolveSupportCapability {
+                            properties: vec!["location.range".into()],
+                        }),
+                        ..Default::default()
+                    }),
+                    execute_command: Some(DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    workspace_edit: Some(WorkspaceEditClientCapabilities {
+                        document_changes: Some(true),
+                        resource_operations: Some(vec![
+                            ResourceOperationKind::Create,
+                            ResourceOperationKind::Rename,
+                            ResourceOperationKind::Delete,
+                        ]),
+                        failure_handling: Some(FailureHandlingKind::Transactional),
+                        ..Default::default()
+                    }),
+                    did_change_configuration: Some(DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    diagnostic: Some(DiagnosticWorkspaceClientCapabilities {
+                        refresh_support: Some(false),
+                    }),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -169,62 +694,178 @@ impl LspClient {
             ..Default::default()
         };
 
-        let response = self
+        let response = match self
             .send_request("initialize", Some(serde_json::to_value(params)?))
-            .await?;
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                settle_stderr().await;
+                anyhow::bail!("Initialize failed: {}{}", e, self.stderr_tail_note())
+            }
+        };
 
-        if response.error.is_some() {
-            anyhow::bail!("Initialize failed: {:?}", response.error);
+        if let Some(error) = response.error {
+            settle_stderr().await;
+            anyhow::bail!("Initialize failed: {}{}", error.message, self.stderr_tail_note());
+        }
+
+        if let Some(result) = response.result {
+            let result: InitializeResult = serde_json::from_value(result)?;
+            *self.server_capabilities.lock().await = Some(result.capabilities);
         }
 
         self.send_notification("initialized", Some(json!({})))
             .await?;
 
-        *self.initialized.lock().await = true;
+        // Servers that don't pull via workspace/configuration read settings from here
+        if let Some(settings) = &self.config.settings {
+            let params = DidChangeConfigurationParams {
+                settings: settings.clone(),
+            };
+            self.send_notification(
+                "workspace/didChangeConfiguration",
+                Some(serde_json::to_value(params)?),
+            )
+            .await?;
+        }
+
+        *initialized = true;
         Ok(())
     }
 
-    pub async fn ensure_initialized(&self, file_path: &Path) -> Result<()> {
-        if *self.initialized.lock().await {
+    /// Tell the server `path` changed on disk, if it has it open: `didChange`
+    /// with the new contents, or `didClose` if the file is gone
+    pub async fn sync_file(&self, path: &Path) -> Result<()> {
+        if !self.documents.lock().await.contains_key(path) {
+            return Ok(());
+        }
+        if path.exists() {
+            self.open_file(path).await?;
             return Ok(());
         }
 
-        // Find project root
-        let root = find_project_root(file_path).unwrap_or_else(|| {
-            file_path
-                .parent()
-                .unwrap_or(Path::new("/"))
-                .to_path_buf()
-        });
-
-        self.initialize(&root).await
+        if let Some(doc) = self.documents.lock().await.remove(path) {
+            self.closed_versions.lock().unwrap().insert(path.to_path_buf(), doc.version);
+        }
+        let params = DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+        };
+        self.send_notification("textDocument/didClose", Some(serde_json::to_value(params)?))
+            .await
     }
 
-    pub async fn open_file(&self, path: &Path) -> Result<()> {
+    /// Bring the server's view of `path` in line with the file on disk:
+    /// `didOpen` the first time, `didChange` when the content changed since,
+    /// nothing otherwise. Evicts the least recently used document (`didClose`)
+    /// when more than `max_open_documents` are open.
+    pub async fn open_file(&self, path: &Path) -> Result<DocumentSync> {
         let content = tokio::fs::read_to_string(path).await?;
+        let hash = content_hash(&content);
         let uri = path_to_uri(path)?;
 
+        let mut documents = self.documents.lock().await;
+
+        if let Some(doc) = documents.get_mut(path) {
+            doc.last_used = Instant::now();
+            if doc.hash == hash {
+                return Ok(DocumentSync::Unchanged(doc.version));
+            }
+
+            doc.version += 1;
+            let change = if self.incremental_sync().await {
+                let (range, text) = text::changed_range(&doc.text, &content);
+                TextDocumentContentChangeEvent {
+                    range: Some(range),
+                    range_length: None,
+                    text,
+                }
+            } else {
+                TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: content.clone(),
+                }
+            };
+            doc.hash = hash;
+            doc.text = content;
+
+            let params = DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier {
+                    uri,
+                    version: doc.version,
+                },
+                content_changes: vec![change],
+            };
+            let version = doc.version;
+            self.send_notification("textDocument/didChange", Some(serde_json::to_value(params)?))
+                .await?;
+            return Ok(DocumentSync::Changed(version));
+        }
+
+        while documents.len() >= self.config.max_open_documents.max(1) {
+            let Some(lru) = documents
+                .iter()
+                .min_by_key(|(_, d)| d.last_used)
+                .map(|(p, _)| p.clone())
+            else {
+                break;
+            };
+            if let Some(doc) = documents.remove(&lru) {
+                self.closed_versions.lock().unwrap().insert(lru.clone(), doc.version);
+            }
+            let params = DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier {
+                    uri: path_to_uri(&lru)?,
+                },
+            };
+            self.send_notification("textDocument/didClose", Some(serde_json::to_value(params)?))
+                .await?;
+        }
+
         let lang_id = path
             .extension()
             .and_then(|e| e.to_str())
             .map(ext_to_language_id)
             .unwrap_or("plaintext");
 
+        let version = self.closed_versions.lock().unwrap().remove(path).map_or(1, |v| v + 1);
         let params = DidOpenTextDocumentParams {
             text_document: TextDocumentItem {
                 uri,
                 language_id: lang_id.to_string(),
-                version: 1,
-                text: content,
+                version,
+                text: content.clone(),
             },
         };
 
         self.send_notification("textDocument/didOpen", Some(serde_json::to_value(params)?))
-            .await
+            .await?;
+        documents.insert(
+            path.to_path_buf(),
+            OpenDocument {
+                version,
+                hash,
+                text: content,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(DocumentSync::Opened(version))
+    }
+
+    /// Whether the server accepts ranged `didChange` events rather than full text
+    async fn incremental_sync(&self) -> bool {
+        let capabilities = self.server_capabilities.lock().await;
+        let kind = match capabilities.as_ref().and_then(|c| c.text_document_sync.as_ref()) {
+            Some(TextDocumentSyncCapability::Kind(kind)) => Some(*kind),
+            Some(TextDocumentSyncCapability::Options(options)) => options.change,
+            None => None,
+        };
+        kind == Some(TextDocumentSyncKind::INCREMENTAL)
     }
 
     pub async fn hover(&self, path: &Path, line: u32, character: u32) -> Result<Option<Hover>> {
-        self.ensure_initialized(path).await?;
+        self.ensure_initialized().await?;
         self.open_file(path).await?;
 
         let uri = path_to_uri(path)?;
@@ -248,13 +889,10 @@ impl LspClient {
         }
     }
 
-    pub async fn definition(
-        &self,
-        path: &Path,
-        line: u32,
-        character: u32,
-    ) -> Result<Option<GotoDefinitionResponse>> {
-        self.ensure_initialized(path).await?;
+    /// `textDocument/definition` or one of its siblings (`typeDefinition`,
+    /// `implementation`, `declaration`), which share params and result types
+    pub async fn goto(&self, method: &str, path: &Path, line: u32, character: u32) -> Result<Option<GotoDefinitionResponse>> {
+        self.ensure_initialized().await?;
         self.open_file(path).await?;
 
         let uri = path_to_uri(path)?;
@@ -269,10 +907,7 @@ impl LspClient {
         };
 
         let response = self
-            .send_request(
-                "textDocument/definition",
-                Some(serde_json::to_value(params)?),
-            )
+            .send_request(method, Some(serde_json::to_value(params)?))
             .await?;
 
         if let Some(result) = response.result {
@@ -282,13 +917,188 @@ impl LspClient {
         }
     }
 
+    /// The call hierarchy item(s) at a position, the starting point for
+    /// `incoming_calls` and `outgoing_calls`
+    pub async fn prepare_call_hierarchy(&self, path: &Path, line: u32, character: u32) -> Result<Option<Vec<CallHierarchyItem>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = CallHierarchyPrepareParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/prepareCallHierarchy", params).await
+    }
+
+    pub async fn incoming_calls(&self, item: CallHierarchyItem) -> Result<Option<Vec<CallHierarchyIncomingCall>>> {
+        let params = CallHierarchyIncomingCallsParams {
+            item,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("callHierarchy/incomingCalls", params).await
+    }
+
+    pub async fn outgoing_calls(&self, item: CallHierarchyItem) -> Result<Option<Vec<CallHierarchyOutgoingCall>>> {
+        let params = CallHierarchyOutgoingCallsParams {
+            item,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("callHierarchy/outgoingCalls", params).await
+    }
+
+    /// The type hierarchy item(s) at a position, the starting point for
+    /// `supertypes` and `subtypes`
+    pub async fn prepare_type_hierarchy(&self, path: &Path, line: u32, character: u32) -> Result<Option<Vec<TypeHierarchyItem>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = TypeHierarchyPrepareParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/prepareTypeHierarchy", params).await
+    }
+
+    pub async fn supertypes(&self, item: TypeHierarchyItem) -> Result<Option<Vec<TypeHierarchyItem>>> {
+        let params = TypeHierarchySupertypesParams {
+            item,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("typeHierarchy/supertypes", params).await
+    }
+
+    pub async fn subtypes(&self, item: TypeHierarchyItem) -> Result<Option<Vec<TypeHierarchyItem>>> {
+        let params = TypeHierarchySubtypesParams {
+            item,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("typeHierarchy/subtypes", params).await
+    }
+
+    pub async fn inlay_hints(&self, path: &Path, range: Range) -> Result<Option<Vec<InlayHint>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = InlayHintParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            range,
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/inlayHint", params).await
+    }
+
+    /// The server's semantic tokens options, including the legend its tokens
+    /// are encoded with
+    async fn semantic_tokens_options(&self) -> Option<SemanticTokensOptions> {
+        match self.server_capabilities.lock().await.as_ref()?.semantic_tokens_provider.clone()? {
+            SemanticTokensServerCapabilities::SemanticTokensOptions(o) => Some(o),
+            SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(r) => Some(r.semantic_tokens_options),
+        }
+    }
+
+    /// Semantic tokens for `range`, or the whole file, with the legend needed
+    /// to decode them. Falls back to the full document when the server can't
+    /// do ranges, so callers should still filter by position.
+    pub async fn semantic_tokens(&self, path: &Path, range: Option<Range>) -> Result<(SemanticTokensLegend, Vec<SemanticToken>)> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let options = self
+            .semantic_tokens_options()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("{} does not support semantic tokens", self.name))?;
+        let text_document = TextDocumentIdentifier { uri: path_to_uri(path)? };
+
+        let tokens = match range.filter(|_| options.range == Some(true)) {
+            Some(range) => {
+                let params = SemanticTokensRangeParams {
+                    text_document,
+                    range,
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                };
+                match self.request("textDocument/semanticTokens/range", params).await? {
+                    Some(SemanticTokensRangeResult::Tokens(t)) => t.data,
+                    Some(SemanticTokensRangeResult::Partial(p)) => p.data,
+                    None => Vec::new(),
+                }
+            }
+            None => {
+                let params = SemanticTokensParams {
+                    text_document,
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                };
+                match self.request("textDocument/semanticTokens/full", params).await? {
+                    Some(SemanticTokensResult::Tokens(t)) => t.data,
+                    Some(SemanticTokensResult::Partial(p)) => p.data,
+                    None => Vec::new(),
+                }
+            }
+        };
+        Ok((options.legend, tokens))
+    }
+
+    /// Occurrences of the symbol at a position within its file, marked as
+    /// reads or writes where the server can tell
+    pub async fn document_highlights(&self, path: &Path, line: u32, character: u32) -> Result<Option<Vec<DocumentHighlight>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = DocumentHighlightParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("textDocument/documentHighlight", params).await
+    }
+
+    pub async fn folding_ranges(&self, path: &Path) -> Result<Option<Vec<FoldingRange>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = FoldingRangeParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("textDocument/foldingRange", params).await
+    }
+
+    /// For each position, the chain of ever larger syntactic ranges around it
+    pub async fn selection_ranges(&self, path: &Path, positions: Vec<Position>) -> Result<Option<Vec<SelectionRange>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = SelectionRangeParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            positions,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("textDocument/selectionRange", params).await
+    }
+
     pub async fn references(
         &self,
         path: &Path,
         line: u32,
         character: u32,
     ) -> Result<Option<Vec<Location>>> {
-        self.ensure_initialized(path).await?;
+        self.ensure_initialized().await?;
         self.open_file(path).await?;
 
         let uri = path_to_uri(path)?;
@@ -320,7 +1130,7 @@ impl LspClient {
     }
 
     pub async fn document_symbols(&self, path: &Path) -> Result<Option<DocumentSymbolResponse>> {
-        self.ensure_initialized(path).await?;
+        self.ensure_initialized().await?;
         self.open_file(path).await?;
 
         let uri = path_to_uri(path)?;
@@ -345,21 +1155,434 @@ impl LspClient {
         }
     }
 
-    pub async fn diagnostics(&self, path: &Path) -> Result<Vec<Diagnostic>> {
-        self.ensure_initialized(path).await?;
+    pub async fn completion(&self, path: &Path, line: u32, character: u32) -> Result<Option<CompletionResponse>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = CompletionParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: Some(CompletionContext {
+                trigger_kind: CompletionTriggerKind::INVOKED,
+                trigger_character: None,
+            }),
+        };
+
+        self.request("textDocument/completion", params).await
+    }
+
+    pub async fn signature_help(&self, path: &Path, line: u32, character: u32) -> Result<Option<SignatureHelp>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = SignatureHelpParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            work_done_progress_params: Default::default(),
+            context: None,
+        };
+
+        self.request("textDocument/signatureHelp", params).await
+    }
+
+    /// Check that the symbol at a position can be renamed. `None` if it
+    /// can't; if the server has no `prepareRename`, assumes it can.
+    pub async fn prepare_rename(
+        &self,
+        path: &Path,
+        line: u32,
+        character: u32,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let prepares = matches!(
+            self.server_capabilities.lock().await.as_ref().and_then(|c| c.rename_provider.as_ref()),
+            Some(OneOf::Right(RenameOptions { prepare_provider: Some(true), .. }))
+        );
+        if !prepares {
+            return Ok(Some(PrepareRenameResponse::DefaultBehavior { default_behavior: true }));
+        }
+
+        let params = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            position: Position { line, character },
+        };
+        self.request("textDocument/prepareRename", params).await
+    }
+
+    pub async fn rename(&self, path: &Path, line: u32, character: u32, new_name: &str) -> Result<Option<WorkspaceEdit>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = RenameParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+                position: Position { line, character },
+            },
+            new_name: new_name.to_string(),
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/rename", params).await
+    }
+
+    /// Symbols anywhere in the workspace matching `query`
+    pub async fn workspace_symbols(&self, query: &str) -> Result<Option<WorkspaceSymbolResponse>> {
+        if !*self.initialized.lock().await {
+            anyhow::bail!("{} is not initialized yet; query one of its files first", self.name);
+        }
+
+        let params = WorkspaceSymbolParams {
+            query: query.to_string(),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("workspace/symbol", params).await
+    }
+
+    /// Fill in the range of a workspace symbol the server located only by
+    /// file. Returns the symbol unchanged if the server doesn't resolve them.
+    pub async fn resolve_workspace_symbol(&self, symbol: WorkspaceSymbol) -> Result<WorkspaceSymbol> {
+        let resolves = matches!(
+            self.server_capabilities.lock().await.as_ref().and_then(|c| c.workspace_symbol_provider.as_ref()),
+            Some(OneOf::Right(WorkspaceSymbolOptions { resolve_provider: Some(true), .. }))
+        );
+        if !resolves || matches!(symbol.location, OneOf::Left(_)) {
+            return Ok(symbol);
+        }
+        self.request("workspaceSymbol/resolve", symbol).await
+    }
+
+    /// Edits that format the whole document
+    pub async fn format(&self, path: &Path, options: FormattingOptions) -> Result<Option<Vec<TextEdit>>> {
+        self.ensure_initialized().await?;
         self.open_file(path).await?;
 
-        // LSP diagnostics are push-based, but we can trigger them by saving
-        // For now, return empty - proper implementation needs notification handling
-        Ok(vec![])
+        let params = DocumentFormattingParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            options,
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/formatting", params).await
+    }
+
+    /// Edits that format `range`
+    pub async fn format_range(&self, path: &Path, range: Range, options: FormattingOptions) -> Result<Option<Vec<TextEdit>>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = DocumentRangeFormattingParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            range,
+            options,
+            work_done_progress_params: Default::default(),
+        };
+        self.request("textDocument/rangeFormatting", params).await
+    }
+
+    /// Formatting options from config, for callers to override
+    pub fn formatting_options(&self) -> FormattingOptions {
+        FormattingOptions {
+            tab_size: self.config.tab_size,
+            insert_spaces: self.config.insert_spaces,
+            ..Default::default()
+        }
+    }
+
+    /// Code actions for `range`, given the diagnostics the user is looking at
+    pub async fn code_actions(
+        &self,
+        path: &Path,
+        range: Range,
+        diagnostics: Vec<Diagnostic>,
+        only: Option<Vec<CodeActionKind>>,
+    ) -> Result<Option<CodeActionResponse>> {
+        self.ensure_initialized().await?;
+        self.open_file(path).await?;
+
+        let params = CodeActionParams {
+            text_document: TextDocumentIdentifier { uri: path_to_uri(path)? },
+            range,
+            context: CodeActionContext {
+                diagnostics,
+                only,
+                trigger_kind: Some(CodeActionTriggerKind::INVOKED),
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        self.request("textDocument/codeAction", params).await
+    }
+
+    /// Fill in a code action's edit if the server computes it lazily
+    pub async fn resolve_code_action(&self, action: CodeAction) -> Result<CodeAction> {
+        let resolves = matches!(
+            self.server_capabilities.lock().await.as_ref().and_then(|c| c.code_action_provider.as_ref()),
+            Some(CodeActionProviderCapability::Options(CodeActionOptions { resolve_provider: Some(true), .. }))
+        );
+        if !resolves || action.edit.is_some() {
+            return Ok(action);
+        }
+        self.request("codeAction/resolve", action).await
+    }
+
+    /// Run a server command. Edits the server asks for while it runs are
+    /// handed to `apply_edit`, instead of being refused, and the server is
+    /// told whether they were applied.
+    pub async fn execute_command<F, Fut>(&self, command: &lsp_types::Command, mut apply_edit: F) -> Result<()>
+    where
+        F: FnMut(WorkspaceEdit) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        self.ensure_initialized().await?;
+        // One command at a time, so each gets only its own edits
+        let _running = self.command_lock.lock().await;
+
+        let (queue, mut edits) = mpsc::unbounded_channel();
+        *self.context.command_edits.lock().unwrap() = Some(queue);
+        let params = ExecuteCommandParams {
+            command: command.command.clone(),
+            arguments: command.arguments.clone().unwrap_or_default(),
+            work_done_progress_params: Default::default(),
+        };
+        // Servers usually wait for our answer to applyEdit before finishing
+        // the command, so edits are applied while we wait for it
+        let request = self.request::<_, Option<Value>>("workspace/executeCommand", params);
+        tokio::pin!(request);
+        let result = loop {
+            tokio::select! {
+                result = &mut request => break result,
+                Some((edit, reply)) = edits.recv() => {
+                    let _ = reply.send(apply_edit(edit).await.map_err(|e| e.to_string()));
+                }
+            }
+        };
+
+        *self.context.command_edits.lock().unwrap() = None;
+        // Edits that arrived along with the command's response
+        while let Ok((edit, reply)) = edits.try_recv() {
+            let _ = reply.send(apply_edit(edit).await.map_err(|e| e.to_string()));
+        }
+        result.map(|_| ())
+    }
+
+    /// Last diagnostics the server reported for `path`, without asking again
+    pub async fn cached_diagnostics(&self, path: &Path) -> Result<Vec<Diagnostic>> {
+        let uri = path_to_uri(path)?;
+        Ok(self.diagnostics.get(&uri).await.map(|d| d.all()).unwrap_or_default())
+    }
+
+    /// Fill in the documentation and detail of a completion item. Returns the
+    /// item unchanged if the server doesn't resolve completions.
+    pub async fn resolve_completion(&self, item: CompletionItem) -> Result<CompletionItem> {
+        let resolves = self
+            .server_capabilities
+            .lock()
+            .await
+            .as_ref()
+            .and_then(|c| c.completion_provider.as_ref())
+            .and_then(|c| c.resolve_provider)
+            .unwrap_or(false);
+        if !resolves {
+            return Ok(item);
+        }
+        self.request("completionItem/resolve", item).await
+    }
+
+    /// Pull diagnostics options, if the server supports LSP 3.17 pull diagnostics
+    async fn diagnostic_options(&self) -> Option<DiagnosticOptions> {
+        match self.server_capabilities.lock().await.as_ref()?.diagnostic_provider.clone()? {
+            DiagnosticServerCapabilities::Options(o) => Some(o),
+            DiagnosticServerCapabilities::RegistrationOptions(r) => Some(r.diagnostic_options),
+        }
+    }
+
+    pub async fn diagnostics(&self, path: &Path) -> Result<Vec<Diagnostic>> {
+        self.ensure_initialized().await?;
+
+        if let Some(options) = self.diagnostic_options().await {
+            self.open_file(path).await?;
+            match self.pull_diagnostics(path, options).await {
+                Ok(d) => return Ok(d),
+                // Fall back to whatever the server pushes
+                Err(e) => eprintln!("[lsp-mcp-rs] {}: pull diagnostics failed: {}", self.name, e),
+            }
+        }
+
+        // Diagnostics are push-based: note where the stream is before
+        // (re)opening, then wait for the server to publish something newer
+        let since = self.diagnostics.current_seq();
+        let sync = self.open_file(path).await?;
+        let uri = path_to_uri(path)?;
+
+        // Nothing changed since we last looked, so the server has nothing new to say
+        if let DocumentSync::Unchanged(_) = sync {
+            if let Some(cached) = self.diagnostics.get(&uri).await {
+                return Ok(cached.all());
+            }
+        }
+
+        // A tool call's own timeout bounds the wait too
+        let timeout = TIMEOUT_OVERRIDE
+            .try_with(|t| *t)
+            .unwrap_or(Duration::from_millis(self.config.diagnostics_timeout_ms));
+        let published = self
+            .diagnostics
+            .wait_fresh(
+                &uri,
+                since,
+                sync.version(),
+                Duration::from_millis(self.config.diagnostics_settle_ms),
+                timeout,
+            )
+            .await;
+
+        Ok(published.map(|p| p.all()).unwrap_or_default())
+    }
+
+    async fn pull_diagnostics(&self, path: &Path, options: DiagnosticOptions) -> Result<Vec<Diagnostic>> {
+        let uri = path_to_uri(path)?;
+
+        let params = DocumentDiagnosticParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            identifier: options.identifier,
+            previous_result_id: self.diagnostics.result_id(&uri).await,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+
+        let response = self
+            .send_request(
+                "textDocument/diagnostic",
+                Some(serde_json::to_value(params)?),
+            )
+            .await?;
+
+        if let Some(error) = response.error {
+            anyhow::bail!("{}", error.message);
+        }
+
+        let result: DocumentDiagnosticReportResult =
+            serde_json::from_value(response.result.unwrap_or(Value::Null))?;
+
+        let related = match result {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) => {
+                let full = report.full_document_diagnostic_report;
+                self.diagnostics
+                    .store_pulled(uri.clone(), full.result_id, full.items)
+                    .await;
+                report.related_documents
+            }
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Unchanged(report)) => {
+                self.diagnostics
+                    .mark_unchanged(&uri, report.unchanged_document_diagnostic_report.result_id)
+                    .await;
+                report.related_documents
+            }
+            DocumentDiagnosticReportResult::Partial(partial) => partial.related_documents,
+        };
+
+        for (related_uri, kind) in related.into_iter().flatten() {
+            match kind {
+                DocumentDiagnosticReportKind::Full(full) => {
+                    self.diagnostics
+                        .store_pulled(related_uri, full.result_id, full.items)
+                        .await
+                }
+                DocumentDiagnosticReportKind::Unchanged(unchanged) => {
+                    self.diagnostics
+                        .mark_unchanged(&related_uri, unchanged.result_id)
+                        .await
+                }
+            }
+        }
+
+        Ok(self
+            .diagnostics
+            .get(&uri)
+            .await
+            .map(|d| d.all())
+            .unwrap_or_default())
+    }
+
+    /// Pull diagnostics for the whole workspace. Only files whose report
+    /// changed since the previous call come back as full reports.
+    pub async fn workspace_diagnostics(&self) -> Result<WorkspaceDiagnosticReport> {
+        if !*self.initialized.lock().await {
+            anyhow::bail!("{} is not initialized yet; query one of its files first", self.name);
+        }
+
+        let options = self
+            .diagnostic_options()
+            .await
+            .filter(|o| o.workspace_diagnostics)
+            .ok_or_else(|| anyhow::anyhow!("{} does not support workspace diagnostics", self.name))?;
+
+        let params = WorkspaceDiagnosticParams {
+            identifier: options.identifier,
+            previous_result_ids: self.diagnostics.previous_result_ids().await,
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+
+        let response = self
+            .send_request(
+                "workspace/diagnostic",
+                Some(serde_json::to_value(params)?),
+            )
+            .await?;
+
+        if let Some(error) = response.error {
+            anyhow::bail!("{}", error.message);
+        }
+
+        let items = match serde_json::from_value(response.result.unwrap_or(Value::Null))? {
+            WorkspaceDiagnosticReportResult::Report(r) => r.items,
+            WorkspaceDiagnosticReportResult::Partial(p) => p.items,
+        };
+
+        for item in &items {
+            match item {
+                WorkspaceDocumentDiagnosticReport::Full(full) => {
+                    let report = &full.full_document_diagnostic_report;
+                    self.diagnostics
+                        .store_pulled(full.uri.clone(), report.result_id.clone(), report.items.clone())
+                        .await
+                }
+                WorkspaceDocumentDiagnosticReport::Unchanged(unchanged) => {
+                    self.diagnostics
+                        .mark_unchanged(
+                            &unchanged.uri,
+                            unchanged.unchanged_document_diagnostic_report.result_id.clone(),
+                        )
+                        .await
+                }
+            }
+        }
+
+        Ok(WorkspaceDiagnosticReport { items })
     }
 
     pub async fn shutdown(&self) -> Result<()> {
         let _ = self.send_request("shutdown", None).await;
         let _ = self.send_notification("exit", None).await;
 
-        if let Some(mut child) = self.process.lock().await.take() {
-            let _ = child.kill().await;
+        // The writer flushes `exit`, then closes stdin on the empty message;
+        // the watcher gives the process a moment to exit before killing it
+        if let Some(writer) = self.writer.lock().await.take() {
+            let _ = writer.send(String::new());
+        }
+        if let Some(kill) = self.kill.lock().await.take() {
+            let _ = kill.send(());
+        }
+        if let Some(watcher) = self.watcher.lock().await.take() {
+            let _ = watcher.await;
         }
 
         *self.initialized.lock().await = false;
@@ -367,6 +1590,16 @@ impl LspClient {
     }
 }
 
+fn workspace_folder(path: &Path) -> Result<WorkspaceFolder> {
+    Ok(WorkspaceFolder {
+        uri: path_to_uri(path)?,
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string()),
+    })
+}
+
 fn path_to_uri(path: &Path) -> Result<Uri> {
     // Canonicalize path and convert to file URI
     let abs_path = if path.is_absolute() {
@@ -416,39 +1649,337 @@ fn path_to_uri(path: &Path) -> Result<Uri> {
 
 pub fn uri_to_path_string(uri: &Uri) -> String {
     let s = uri.as_str();
-    if s.starts_with("file:///") {
-        let path = &s[7..]; // Keep one slash
+    if let Some(path) = s.strip_prefix("file://") {
+        let path = percent_decode(path);
         // Handle Windows paths like file:///C:/...
-        if path.len() > 2 && path.chars().nth(1) == Some(':') {
-            path.to_string()
+        if path.len() > 2 && path.chars().nth(2) == Some(':') {
+            path[1..].to_string()
         } else {
-            path.to_string()
+            path
         }
     } else {
         s.to_string()
     }
 }
 
-fn find_project_root(path: &Path) -> Option<std::path::PathBuf> {
-    let markers = [
-        ".git",
-        "Cargo.toml",
-        "package.json",
-        "pyproject.toml",
-        "go.mod",
-        ".luarc.json",
-        "compile_commands.json",
-    ];
-
-    let mut current = path.parent()?;
-    loop {
-        for marker in &markers {
-            if current.join(marker).exists() {
-                return Some(current.to_path_buf());
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = |b: u8| (b as char).to_digit(16);
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// What `open_file` had to tell the server
+#[derive(Debug, Clone, Copy)]
+pub enum DocumentSync {
+    Opened(i32),
+    Changed(i32),
+    Unchanged(i32),
+}
+
+impl DocumentSync {
+    pub fn version(self) -> i32 {
+        match self {
+            DocumentSync::Opened(v) | DocumentSync::Changed(v) | DocumentSync::Unchanged(v) => v,
+        }
+    }
+}
+
+struct OpenDocument {
+    version: i32,
+    hash: u64,
+    /// Last text sent, needed to compute incremental changes
+    text: String,
+    last_used: Instant,
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Servers usually log why they failed right before failing; give the stderr
+/// task a moment to catch up before we quote it
+async fn settle_stderr() {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// Forgets a pending request and sends `$/cancelRequest` for it when dropped.
+/// `send_request` disarms it once a response arrives.
+struct CancelOnDrop {
+    id: i64,
+    pending: Arc<Mutex<HashMap<i64, oneshot::Sender<JsonRpcResponse>>>>,
+    writer: mpsc::UnboundedSender<String>,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let id = self.id;
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            pending.lock().await.remove(&id);
+        });
+
+        let cancel = JsonRpcNotification::new("$/cancelRequest", Some(json!({ "id": id })));
+        let _ = self.writer.send(encode_message(&cancel));
+    }
+}
+
+/// Client state visible to server-request handlers on the reader task
+struct HandlerContext {
+    settings: Option<Value>,
+    workspace_folders: std::sync::RwLock<Vec<WorkspaceFolder>>,
+    /// Where `workspace/applyEdit` requests go while one of our
+    /// `workspace/executeCommand` calls runs; `None` the rest of the time
+    command_edits: std::sync::Mutex<Option<EditQueue>>,
+}
+
+/// An edit for `execute_command` to apply, and where to send how that went
+type EditQueue = mpsc::UnboundedSender<(WorkspaceEdit, oneshot::Sender<Result<(), String>>)>;
+
+type RequestHandler = fn(&HandlerContext, Option<Value>) -> Result<Value, JsonRpcError>;
+
+/// Replies to requests the server sends us (bar `workspace/applyEdit`, which
+/// the reader task answers asynchronously). Anything not listed here gets a
+/// MethodNotFound error rather than being left unanswered.
+fn request_handlers() -> HashMap<&'static str, RequestHandler> {
+    let mut handlers: HashMap<&'static str, RequestHandler> = HashMap::new();
+    handlers.insert("workspace/configuration", handle_configuration);
+    handlers.insert("workspace/workspaceFolders", handle_workspace_folders);
+    handlers.insert("window/showDocument", |_, _| Ok(json!({ "success": false })));
+    // Requests that only need an acknowledgement
+    for method in [
+        "window/workDoneProgress/create",
+        "window/showMessageRequest",
+        "client/registerCapability",
+        "client/unregisterCapability",
+        "workspace/diagnostic/refresh",
+        "workspace/semanticTokens/refresh",
+        "workspace/inlayHint/refresh",
+        "workspace/inlineValue/refresh",
+        "workspace/codeLens/refresh",
+    ] {
+        handlers.insert(method, |_, _| Ok(Value::Null));
+    }
+    handlers
+}
+
+fn handle_configuration(context: &HandlerContext, params: Option<Value>) -> Result<Value, JsonRpcError> {
+    let params: ConfigurationParams = params
+        .and_then(|p| serde_json::from_value(p).ok())
+        .ok_or_else(|| JsonRpcError::new(JsonRpcError::INVALID_PARAMS, "Invalid configuration params"))?;
+
+    // Unknown sections get `null`, which tells the server to use its defaults
+    let results = params
+        .items
+        .iter()
+        .map(|item| match &context.settings {
+            Some(settings) => settings_section(settings, item.section.as_deref()),
+            None => Value::Null,
+        })
+        .collect();
+    Ok(Value::Array(results))
+}
+
+/// Look up a dotted section like `rust-analyzer.cargo` in the settings table.
+/// A key that itself contains dots (`"python.analysis"`) matches as a whole.
+fn settings_section(settings: &Value, section: Option<&str>) -> Value {
+    let Some(section) = section.filter(|s| !s.is_empty()) else {
+        return settings.clone();
+    };
+    let mut current = settings;
+    let mut parts = section.split('.').peekable();
+    while let Some(part) = parts.next() {
+        // Try the longest dotted key first: "a.b.c" may be stored as "a.b" -> "c"
+        let rest: Vec<&str> = parts.clone().collect();
+        let mut found = None;
+        for take in (0..=rest.len()).rev() {
+            let key = std::iter::once(part).chain(rest[..take].iter().copied()).collect::<Vec<_>>().join(".");
+            if let Some(next) = current.get(&key) {
+                found = Some((next, take));
+                break;
+            }
+        }
+        let Some((next, take)) = found else {
+            return Value::Null;
+        };
+        for _ in 0..take {
+            parts.next();
+        }
+        current = next;
+    }
+    current.clone()
+}
+
+fn handle_workspace_folders(context: &HandlerContext, _: Option<Value>) -> Result<Value, JsonRpcError> {
+    let folders = context.workspace_folders.read().unwrap();
+    if folders.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(serde_json::to_value(&*folders).unwrap_or(Value::Null))
+    }
+}
+
+/// Edits are only accepted while a command we asked for is running. They
+/// go to `execute_command`, and the answer waits until they are written.
+async fn handle_apply_edit(context: &HandlerContext, params: Option<Value>) -> Result<Value, JsonRpcError> {
+    let params: ApplyWorkspaceEditParams = serde_json::from_value(params.unwrap_or(Value::Null))
+        .map_err(|e| JsonRpcError::new(JsonRpcError::INVALID_PARAMS, e.to_string()))?;
+
+    let queue = context.command_edits.lock().unwrap().clone();
+    let (reply, outcome) = oneshot::channel();
+    if queue.is_none_or(|queue| queue.send((params.edit, reply)).is_err()) {
+        return Ok(json!({
+            "applied": false,
+            "failureReason": "lsp-mcp-rs only applies edits from commands it runs"
+        }));
+    }
+    match outcome.await {
+        Ok(Ok(())) => Ok(json!({ "applied": true })),
+        Ok(Err(reason)) => Ok(json!({ "applied": false, "failureReason": reason })),
+        Err(_) => Ok(json!({
+            "applied": false,
+            "failureReason": "The command finished before the edit could be applied"
+        })),
+    }
+}
+
+async fn handle_notification(diagnostics: &DiagnosticsStore, notification: JsonRpcNotification) {
+    if notification.method == "textDocument/publishDiagnostics" {
+        if let Some(params) = notification
+            .params
+            .and_then(|p| serde_json::from_value::<PublishDiagnosticsParams>(p).ok())
+        {
+            diagnostics.publish(params).await;
+        }
+    }
+}
+
+/// Markers used when a server has no `root_patterns`, or none of them match
+const DEFAULT_ROOT_MARKERS: &[&str] = &[
+    ".git",
+    "Cargo.toml",
+    "package.json",
+    "pyproject.toml",
+    "go.mod",
+    ".luarc.json",
+    "compile_commands.json",
+];
+
+/// Find the project root for `path`: the innermost or outermost ancestor
+/// directory (per `policy`) containing something matching one of `patterns`.
+/// Patterns may contain `*`, `?` and `[...]` and span subdirectories
+/// (`build/compile_commands.json`, `*.sln`).
+pub fn find_project_root(path: &Path, patterns: &[String], policy: RootPolicy) -> Option<PathBuf> {
+    if !patterns.is_empty() {
+        if let Some(root) = find_root_with(path, patterns, policy) {
+            return Some(root);
+        }
+    }
+    let defaults: Vec<String> = DEFAULT_ROOT_MARKERS.iter().map(|m| m.to_string()).collect();
+    find_root_with(path, &defaults, policy)
+}
+
+fn find_root_with(path: &Path, patterns: &[String], policy: RootPolicy) -> Option<PathBuf> {
+    let mut found = None;
+    for dir in path.ancestors().skip(1) {
+        if patterns.iter().any(|p| dir_contains_match(dir, p)) {
+            found = Some(dir.to_path_buf());
+            if policy == RootPolicy::Innermost {
+                break;
             }
         }
-        current = current.parent()?;
     }
+    found
+}
+
+fn dir_contains_match(dir: &Path, pattern: &str) -> bool {
+    let components: Vec<&str> = pattern
+        .split(['/', '\\'])
+        .filter(|c| !c.is_empty())
+        .collect();
+    !components.is_empty() && matches_below(dir, &components)
+}
+
+fn matches_below(dir: &Path, components: &[&str]) -> bool {
+    let Some((first, rest)) = components.split_first() else {
+        return true;
+    };
+
+    if !first.contains(['*', '?', '[']) {
+        let candidate = dir.join(first);
+        return if rest.is_empty() {
+            candidate.exists()
+        } else {
+            candidate.is_dir() && matches_below(&candidate, rest)
+        };
+    }
+
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name();
+        glob_match(first, &name.to_string_lossy())
+            && (rest.is_empty() || (entry.path().is_dir() && matches_below(&entry.path(), rest)))
+    })
+}
+
+/// Match a single path component against a glob supporting `*`, `?` and `[...]`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match p.split_first() {
+            None => n.is_empty(),
+            Some(('*', rest)) => (0..=n.len()).any(|i| matches(rest, &n[i..])),
+            Some(('?', rest)) => !n.is_empty() && matches(rest, &n[1..]),
+            Some(('[', rest)) => {
+                let Some(close) = rest.iter().position(|&c| c == ']') else {
+                    return n.first() == Some(&'[') && matches(rest, &n[1..]);
+                };
+                let Some(&c) = n.first() else {
+                    return false;
+                };
+                let (negate, class) = match rest[..close].split_first() {
+                    Some(('!', class)) | Some(('^', class)) => (true, class),
+                    _ => (false, &rest[..close]),
+                };
+                let mut hit = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        hit |= class[i] <= c && c <= class[i + 2];
+                        i += 3;
+                    } else {
+                        hit |= class[i] == c;
+                        i += 1;
+                    }
+                }
+                hit != negate && matches(&rest[close + 1..], &n[1..])
+            }
+            Some((&c, rest)) => n.first() == Some(&c) && matches(rest, &n[1..]),
+        }
+    }
+
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    matches(&p, &n)
 }
 
 fn ext_to_language_id(ext: &str) -> &str {
@@ -469,3 +2000,106 @@ fn ext_to_language_id(ext: &str) -> &str {
         _ => "plaintext",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory with `files` (relative paths) created inside it
+    fn tree(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsp-mcp-rs-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn settings_sections_resolve_dotted_paths() {
+        let settings = json!({
+            "rust-analyzer": { "cargo": { "features": ["a"] }, "check": { "command": "clippy" } },
+            "python.analysis": { "extraPaths": ["x"] },
+        });
+
+        assert_eq!(settings_section(&settings, None), settings);
+        assert_eq!(settings_section(&settings, Some("")), settings);
+        assert_eq!(settings_section(&settings, Some("rust-analyzer.cargo")), json!({ "features": ["a"] }));
+        assert_eq!(settings_section(&settings, Some("rust-analyzer.check.command")), json!("clippy"));
+        // A key with dots in it matches whole, and can be descended into
+        assert_eq!(settings_section(&settings, Some("python.analysis")), json!({ "extraPaths": ["x"] }));
+        assert_eq!(settings_section(&settings, Some("python.analysis.extraPaths")), json!(["x"]));
+        // Misses are null, as the spec asks for unknown sections
+        assert_eq!(settings_section(&settings, Some("gopls")), Value::Null);
+        assert_eq!(settings_section(&settings, Some("rust-analyzer.cargo.target")), Value::Null);
+        assert_eq!(settings_section(&settings, Some("rust-analyzer.check.command.x")), Value::Null);
+    }
+
+    #[test]
+    fn glob_matches_one_component() {
+        assert!(glob_match("Cargo.toml", "Cargo.toml"));
+        assert!(!glob_match("Cargo.toml", "Cargo.lock"));
+        assert!(glob_match("*.sln", "App.sln"));
+        assert!(glob_match("*.sln", ".sln"));
+        assert!(!glob_match("*.sln", "App.sln.bak"));
+        assert!(glob_match("?o.mod", "go.mod"));
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[!a-c]x", "bx"));
+        // No directory to cross within a component, so `**` is just `*`
+        assert!(glob_match("**", "anything"));
+        assert!(glob_match("**.sln", "App.sln"));
+    }
+
+    #[test]
+    fn policy_picks_the_innermost_or_outermost_marker() {
+        let dir = tree("root-policy", &["outer/marker", "outer/inner/marker", "outer/inner/src/main.rs"]);
+        let file = dir.join("outer/inner/src/main.rs");
+        let markers = patterns(&["marker"]);
+
+        assert_eq!(find_project_root(&file, &markers, RootPolicy::Innermost), Some(dir.join("outer/inner")));
+        assert_eq!(find_project_root(&file, &markers, RootPolicy::Outermost), Some(dir.join("outer")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn glob_markers_follow_the_policy_too() {
+        let dir = tree("root-glob", &["outer/All.sln", "outer/inner/App.sln", "outer/inner/src/main.rs"]);
+        let file = dir.join("outer/inner/src/main.rs");
+        let markers = patterns(&["*.sln"]);
+
+        assert_eq!(find_project_root(&file, &markers, RootPolicy::Innermost), Some(dir.join("outer/inner")));
+        assert_eq!(find_project_root(&file, &markers, RootPolicy::Outermost), Some(dir.join("outer")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn markers_may_name_subdirectories() {
+        let dir = tree("root-subdir", &["proj/build/compile_commands.json", "proj/src/main.c"]);
+        let file = dir.join("proj/src/main.c");
+
+        let root = find_project_root(&file, &patterns(&["build/compile_commands.json"]), RootPolicy::Innermost);
+        assert_eq!(root, Some(dir.join("proj")));
+        let root = find_project_root(&file, &patterns(&["*/compile_commands.json"]), RootPolicy::Innermost);
+        assert_eq!(root, Some(dir.join("proj")));
+        // `**` matches one directory name, like `*`
+        let root = find_project_root(&file, &patterns(&["**/compile_commands.json"]), RootPolicy::Innermost);
+        assert_eq!(root, Some(dir.join("proj")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unmatched_patterns_fall_back_to_default_markers() {
+        let dir = tree("root-default", &["proj/Cargo.toml", "proj/src/main.rs"]);
+        let file = dir.join("proj/src/main.rs");
+
+        let root = find_project_root(&file, &patterns(&["nothing.here"]), RootPolicy::Innermost);
+        assert_eq!(root, Some(dir.join("proj")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::path::Path;
 
@@ -14,16 +15,123 @@ pub struct ServerConfig {
     #[serde(default)]
     pub args: Vec<String>,
     pub extensions: Vec<String>,
+    /// Files or globs marking a project root, e.g. `["Cargo.toml", "*.sln"]`;
+    /// falls back to built-in markers when empty or nothing matches
     #[serde(default)]
     pub root_patterns: Vec<String>,
+    /// Which matching ancestor wins when several do
+    #[serde(default)]
+    pub root_policy: RootPolicy,
+    /// Default timeout for every request to this server
     #[serde(default = "default_timeout")]
     pub timeout_ms: u64,
+    /// Per-method overrides of `timeout_ms`, e.g. `"workspace/symbol" = 120000`
+    #[serde(default)]
+    pub method_timeouts: HashMap<String, u64>,
+    /// How long `lsp_diagnostics` waits for the server to publish
+    #[serde(default = "default_diagnostics_timeout")]
+    pub diagnostics_timeout_ms: u64,
+    /// Quiet period after the last publish before diagnostics are returned
+    #[serde(default = "default_diagnostics_settle")]
+    pub diagnostics_settle_ms: u64,
+    /// Crashes tolerated within `restart_window_secs` before giving up on the server
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    #[serde(default = "default_restart_window")]
+    pub restart_window_secs: u64,
+    /// Delay before the first restart; doubles with each restart in the window
+    #[serde(default = "default_restart_backoff")]
+    pub restart_backoff_ms: u64,
+    /// Lines of stderr kept per server for `lsp_server_logs`
+    #[serde(default = "default_stderr_lines")]
+    pub stderr_lines: usize,
+    /// Level stderr lines are forwarded to tracing at: trace..error, or "off"
+    #[serde(default = "default_stderr_log_level")]
+    pub stderr_log_level: String,
+    /// Documents kept open on the server; the least recently used is closed beyond this
+    #[serde(default = "default_max_open_documents")]
+    pub max_open_documents: usize,
+    /// Instances (one per project root) kept running at once; the least
+    /// recently used idle one is shut down to make room
+    #[serde(default = "default_max_instances")]
+    pub max_instances: usize,
+    /// Let a running instance serve additional project roots as workspace
+    /// folders (when the server supports it) instead of spawning another
+    #[serde(default = "default_true")]
+    pub multi_root: bool,
+    /// Indent width for `lsp_format` / `lsp_format_range` unless the call says otherwise
+    #[serde(default = "default_tab_size")]
+    pub tab_size: u32,
+    /// Indent with spaces rather than tabs when formatting
+    #[serde(default = "default_true")]
+    pub insert_spaces: bool,
+    /// Sent verbatim as `initializationOptions` in `initialize`
+    #[serde(default)]
+    pub initialization_options: Option<Value>,
+    /// Workspace settings, served section-by-section from `workspace/configuration`
+    /// and pushed via `workspace/didChangeConfiguration`
+    #[serde(default)]
+    pub settings: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootPolicy {
+    /// Closest ancestor to the file (e.g. the crate, not the workspace)
+    #[default]
+    Innermost,
+    /// Furthest ancestor from the file (e.g. the workspace `Cargo.toml`)
+    Outermost,
 }
 
 fn default_timeout() -> u64 {
     30000
 }
 
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_restart_window() -> u64 {
+    300
+}
+
+fn default_restart_backoff() -> u64 {
+    500
+}
+
+fn default_stderr_lines() -> usize {
+    500
+}
+
+fn default_stderr_log_level() -> String {
+    "debug".into()
+}
+
+fn default_max_open_documents() -> usize {
+    50
+}
+
+fn default_max_instances() -> usize {
+    4
+}
+
+fn default_tab_size() -> u32 {
+    4
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_diagnostics_timeout() -> u64 {
+    5000
+}
+
+fn default_diagnostics_settle() -> u64 {
+    500
+}
+
 impl Config {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
         let content = std::fs::read_to_string(path.as_ref())
@@ -0,0 +1,223 @@
+//! Per-URI cache of diagnostics, fed both by `textDocument/publishDiagnostics`
+//! pushes and by `textDocument/diagnostic` / `workspace/diagnostic` pulls
+
+use lsp_types::{Diagnostic, PreviousResultId, PublishDiagnosticsParams, Uri};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Default)]
+pub struct FileDiagnostics {
+    /// Document version the server last pushed for, if it told us
+    pub version: Option<i32>,
+    /// Last set published by the server
+    pub pushed: Vec<Diagnostic>,
+    /// Last full pull report. Kept apart from `pushed` because some servers
+    /// (rust-analyzer) pull native diagnostics but push `cargo check` results.
+    pub pulled: Vec<Diagnostic>,
+    /// Result id of the last pull report, sent back so the server can answer "unchanged"
+    pub result_id: Option<String>,
+    /// Store-wide sequence number of the last update to this entry
+    pub seq: u64,
+}
+
+impl FileDiagnostics {
+    pub fn all(&self) -> Vec<Diagnostic> {
+        self.pushed.iter().chain(&self.pulled).cloned().collect()
+    }
+}
+
+#[derive(Default)]
+pub struct DiagnosticsStore {
+    entries: Mutex<HashMap<Uri, FileDiagnostics>>,
+    seq: AtomicU64,
+    updated: Notify,
+}
+
+impl DiagnosticsStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn publish(&self, params: PublishDiagnosticsParams) {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut entries = self.entries.lock().await;
+
+        // Servers may publish out of order; never let an older version win
+        if let (Some(existing), Some(new)) = (
+            entries.get(&params.uri).and_then(|e| e.version),
+            params.version,
+        ) {
+            if new < existing {
+                return;
+            }
+        }
+
+        let entry = entries.entry(params.uri).or_default();
+        entry.version = params.version;
+        entry.pushed = params.diagnostics;
+        entry.seq = seq;
+        drop(entries);
+
+        self.updated.notify_waiters();
+    }
+
+    /// Record a full pull report for `uri`
+    pub async fn store_pulled(&self, uri: Uri, result_id: Option<String>, diagnostics: Vec<Diagnostic>) {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut entries = self.entries.lock().await;
+        let entry = entries.entry(uri).or_default();
+        entry.pulled = diagnostics;
+        entry.result_id = result_id;
+        entry.seq = seq;
+    }
+
+    /// Record an "unchanged" pull report: the cached items stay, only the id moves on
+    pub async fn mark_unchanged(&self, uri: &Uri, result_id: String) {
+        if let Some(entry) = self.entries.lock().await.get_mut(uri) {
+            entry.result_id = Some(result_id);
+        }
+    }
+
+    pub async fn result_id(&self, uri: &Uri) -> Option<String> {
+        self.entries.lock().await.get(uri).and_then(|e| e.result_id.clone())
+    }
+
+    /// Result ids for every URI we hold a pull report for, for `workspace/diagnostic`
+    pub async fn previous_result_ids(&self) -> Vec<PreviousResultId> {
+        self.entries
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(uri, e)| {
+                e.result_id.clone().map(|value| PreviousResultId {
+                    uri: uri.clone(),
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Sequence number of the most recent update for any URI
+    pub fn current_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub async fn get(&self, uri: &Uri) -> Option<FileDiagnostics> {
+        self.entries.lock().await.get(uri).cloned()
+    }
+
+    /// Wait for diagnostics on `uri` that were published after `since` and
+    /// cover at least `version`, then keep collecting until the server has
+    /// been quiet for `settle`. Never waits longer than `timeout` in total;
+    /// returns whatever is cached at that point (possibly stale or nothing).
+    pub async fn wait_fresh(
+        &self,
+        uri: &Uri,
+        since: u64,
+        version: i32,
+        settle: Duration,
+        timeout: Duration,
+    ) -> Option<FileDiagnostics> {
+        let deadline = Instant::now() + timeout;
+        let mut last_seen = since;
+        let mut settle_deadline: Option<Instant> = None;
+
+        loop {
+            // Register interest before checking, so a publish in between isn't lost
+            let notified = self.updated.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(entry) = self.get(uri).await {
+                let fresh = entry.seq > last_seen && entry.version.is_none_or(|v| v >= version);
+                if fresh {
+                    last_seen = entry.seq;
+                    settle_deadline = Some(Instant::now() + settle);
+                }
+            }
+
+            let wake = settle_deadline.map_or(deadline, |s| s.min(deadline));
+            if tokio::time::timeout_at(wake, notified).await.is_err() {
+                break;
+            }
+        }
+
+        self.get(uri).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn uri() -> Uri {
+        "file:///project/main.rs".parse().unwrap()
+    }
+
+    fn publish(message: &str, version: Option<i32>) -> PublishDiagnosticsParams {
+        let diagnostic = Diagnostic {
+            message: message.into(),
+            ..Default::default()
+        };
+        PublishDiagnosticsParams::new(uri(), vec![diagnostic], version)
+    }
+
+    fn messages(entry: Option<FileDiagnostics>) -> Vec<String> {
+        entry.unwrap().all().into_iter().map(|d| d.message).collect()
+    }
+
+    #[tokio::test]
+    async fn stale_publishes_are_ignored() {
+        let store = DiagnosticsStore::new();
+        store.publish(publish("v2", Some(2))).await;
+        store.publish(publish("v1", Some(1))).await;
+        assert_eq!(messages(store.get(&uri()).await), ["v2"]);
+
+        store.publish(publish("v2 again", Some(2))).await;
+        assert_eq!(messages(store.get(&uri()).await), ["v2 again"]);
+        // Without a version there is nothing to compare, so it wins
+        store.publish(publish("unversioned", None)).await;
+        assert_eq!(messages(store.get(&uri()).await), ["unversioned"]);
+    }
+
+    #[tokio::test]
+    async fn wait_fresh_collects_until_the_server_settles() {
+        let store = Arc::new(DiagnosticsStore::new());
+        let since = store.current_seq();
+        let publisher = store.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            publisher.publish(publish("first", Some(1))).await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            publisher.publish(publish("second", Some(1))).await;
+        });
+
+        let start = Instant::now();
+        let entry = store
+            .wait_fresh(&uri(), since, 1, Duration::from_millis(100), Duration::from_secs(5))
+            .await;
+        assert_eq!(messages(entry), ["second"]);
+        // Done once quiet for `settle` after the last publish, long before the timeout
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn wait_fresh_ignores_older_versions_until_the_timeout() {
+        let store = Arc::new(DiagnosticsStore::new());
+        let since = store.current_seq();
+        store.publish(publish("old", Some(1))).await;
+
+        let start = Instant::now();
+        let entry = store
+            .wait_fresh(&uri(), since, 2, Duration::from_millis(10), Duration::from_millis(150))
+            .await;
+        // Still the stale entry, but only after waiting out the timeout for a newer one
+        assert_eq!(messages(entry), ["old"]);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,234 @@
+//! Line-based unified diffs, for previewing edits before they are written
+
+/// Lines of context around each change
+const CONTEXT: usize = 3;
+
+/// Edit distance beyond which we stop searching for a minimal diff and just
+/// replace everything. The search keeps O(D²) state, which a whole-file
+/// reformat would otherwise blow up.
+const MAX_EDIT_DISTANCE: isize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Unified diff from `old` to `new`, labelled with `path`. `None` stands for
+/// a file that doesn't exist (created or deleted). Empty if nothing changed.
+pub fn unified_diff(path: &str, old: Option<&str>, new: Option<&str>) -> String {
+    let a: Vec<&str> = old.unwrap_or_default().split_inclusive('\n').collect();
+    let b: Vec<&str> = new.unwrap_or_default().split_inclusive('\n').collect();
+    let ops = myers(&a, &b);
+    if ops.iter().all(|op| *op == Op::Equal) {
+        return String::new();
+    }
+
+    let mut out = format!(
+        "--- {}\n+++ {}\n",
+        if old.is_some() { path } else { "/dev/null" },
+        if new.is_some() { path } else { "/dev/null" },
+    );
+
+    // Line numbers in `a` and `b` before each op
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        positions.push((i, j));
+        match op {
+            Op::Equal => (i, j) = (i + 1, j + 1),
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    let changes: Vec<usize> = (0..ops.len()).filter(|&k| ops[k] != Op::Equal).collect();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        // Changes closer than twice the context share a hunk
+        let mut group_end = group_start;
+        while group_end + 1 < changes.len() && changes[group_end + 1] - changes[group_end] <= 2 * CONTEXT + 1 {
+            group_end += 1;
+        }
+        let from = changes[group_start].saturating_sub(CONTEXT);
+        let to = (changes[group_end] + CONTEXT + 1).min(ops.len());
+
+        let (old_start, new_start) = positions[from];
+        let (old_end, new_end) = positions[to];
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start)
+        ));
+        for k in from..to {
+            let (i, j) = positions[k];
+            let (sign, line) = match ops[k] {
+                Op::Equal => (' ', a[i]),
+                Op::Delete => ('-', a[i]),
+                Op::Insert => ('+', b[j]),
+            };
+            out.push(sign);
+            out.push_str(line.strip_suffix('\n').unwrap_or(line));
+            out.push('\n');
+        }
+        group_start = group_end + 1;
+    }
+    out
+}
+
+/// `start,count` as in a hunk header; an empty range names the line before it
+fn hunk_range(start: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, count)
+    }
+}
+
+/// Shortest edit script from `a` to `b` (Myers' O((N+M)D) algorithm), or a
+/// full replacement if that needs more than `MAX_EDIT_DISTANCE` edits
+fn myers(a: &[&str], b: &[&str]) -> Vec<Op> {
+    // Created or deleted files need no search
+    if a.is_empty() || b.is_empty() {
+        return replace_all(a, b);
+    }
+
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // Round d only reads diagonals -(d+1)..=d+1, so that is all we keep of it
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: for d in 0..=max {
+        if d > MAX_EDIT_DISTANCE {
+            return replace_all(a, b);
+        }
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let get = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = get(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Delete all of `a`, insert all of `b`
+fn replace_all(a: &[&str], b: &[&str]) -> Vec<Op> {
+    std::iter::repeat_n(Op::Delete, a.len())
+        .chain(std::iter::repeat_n(Op::Insert, b.len()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: std::ops::RangeInclusive<usize>) -> String {
+        lines.map(|i| format!("{}\n", i)).collect()
+    }
+
+    #[test]
+    fn no_change_is_empty() {
+        assert_eq!(unified_diff("f", Some("a\nb\n"), Some("a\nb\n")), "");
+    }
+
+    #[test]
+    fn one_change_gets_three_lines_of_context() {
+        let old = numbered(1..=10);
+        let new = old.replace("6\n", "six\n");
+        assert_eq!(
+            unified_diff("f", Some(&old), Some(&new)),
+            "--- f\n+++ f\n@@ -3,7 +3,7 @@\n 3\n 4\n 5\n-6\n+six\n 7\n 8\n 9\n"
+        );
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old = numbered(1..=20);
+        let new = old.replace("\n2\n", "\ntwo\n").replace("\n18\n", "\neighteen\n");
+        let diff = unified_diff("f", Some(&old), Some(&new));
+        let headers: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -1,5 +1,5 @@", "@@ -15,6 +15,6 @@"]);
+    }
+
+    #[test]
+    fn close_changes_share_a_hunk() {
+        let old = numbered(1..=20);
+        let new = old.replace("\n5\n", "\nfive\n").replace("\n11\n", "\neleven\n");
+        let diff = unified_diff("f", Some(&old), Some(&new));
+        let headers: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -2,13 +2,13 @@"]);
+    }
+
+    #[test]
+    fn pure_insertion_names_the_line_before() {
+        let diff = unified_diff("f", Some("a\nb\n"), Some("a\nx\nb\n"));
+        assert!(diff.contains("@@ -1,2 +1,3 @@\n a\n+x\n b\n"), "{}", diff);
+    }
+
+    #[test]
+    fn created_and_deleted_files() {
+        assert_eq!(
+            unified_diff("f", None, Some("a\nb\n")),
+            "--- /dev/null\n+++ f\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+        assert_eq!(
+            unified_diff("f", Some("a\nb\n"), None),
+            "--- f\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn missing_final_newline() {
+        assert_eq!(unified_diff("f", Some("a"), Some("b")), "--- f\n+++ f\n@@ -1,1 +1,1 @@\n-a\n+b\n");
+    }
+
+    #[test]
+    fn huge_rewrites_fall_back_to_a_full_replacement() {
+        let old = numbered(1..=1500);
+        let new: String = (1..=1500).map(|i| format!("new {}\n", i)).collect();
+        let diff = unified_diff("f", Some(&old), Some(&new));
+        let headers: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -1,1500 +1,1500 @@"]);
+        assert_eq!(diff.lines().filter(|l| l.starts_with('-') && !l.starts_with("---")).count(), 1500);
+        assert_eq!(diff.lines().filter(|l| l.starts_with('+') && !l.starts_with("+++")).count(), 1500);
+    }
+}
@@ -0,0 +1,365 @@
+//! Turning a `WorkspaceEdit` into new file contents, and writing those to disk
+
+use anyhow::{Context, Result};
+use lsp_types::{
+    DocumentChangeOperation, DocumentChanges, OneOf, ResourceOp, TextDocumentEdit, TextEdit, Uri,
+    WorkspaceEdit,
+};
+use std::path::{Path, PathBuf};
+
+use crate::client::uri_to_path_string;
+use crate::diff;
+use crate::text;
+
+/// One file's contents before and after an edit; `None` means it doesn't exist
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl FileChange {
+    pub fn diff(&self) -> String {
+        diff::unified_diff(&self.path.display().to_string(), self.old.as_deref(), self.new.as_deref())
+    }
+}
+
+/// Work out what `edit` does to each file it touches, reading them from
+/// disk. Nothing is written. Files the edit leaves as they were are omitted.
+pub fn plan(edit: &WorkspaceEdit) -> Result<Vec<FileChange>> {
+    let mut changes: Vec<FileChange> = Vec::new();
+
+    if let Some(document_changes) = &edit.document_changes {
+        let operations: Vec<DocumentChangeOperation> = match document_changes {
+            DocumentChanges::Edits(edits) => edits.iter().cloned().map(DocumentChangeOperation::Edit).collect(),
+            DocumentChanges::Operations(operations) => operations.clone(),
+        };
+        for operation in operations {
+            match operation {
+                DocumentChangeOperation::Edit(edit) => apply_document_edit(&mut changes, &edit)?,
+                DocumentChangeOperation::Op(op) => apply_resource_op(&mut changes, &op)?,
+            }
+        }
+    } else if let Some(edits) = &edit.changes {
+        // Sorted, so previews list files in a stable order
+        let mut edits: Vec<_> = edits.iter().collect();
+        edits.sort_by_key(|(uri, _)| uri.as_str());
+        for (uri, edits) in edits {
+            let change = entry(&mut changes, &to_path(uri))?;
+            let current = change.new.as_deref().with_context(|| format!("{} does not exist", change.path.display()))?;
+            change.new = Some(apply_text_edits(current, edits)?);
+        }
+    }
+
+    changes.retain(|c| c.old != c.new);
+    Ok(changes)
+}
+
+fn apply_document_edit(changes: &mut Vec<FileChange>, edit: &TextDocumentEdit) -> Result<()> {
+    let edits: Vec<TextEdit> = edit
+        .edits
+        .iter()
+        .map(|e| match e {
+            OneOf::Left(e) => e.clone(),
+            OneOf::Right(annotated) => annotated.text_edit.clone(),
+        })
+        .collect();
+    let change = entry(changes, &to_path(&edit.text_document.uri))?;
+    let current = change.new.as_deref().with_context(|| format!("{} does not exist", change.path.display()))?;
+    change.new = Some(apply_text_edits(current, &edits)?);
+    Ok(())
+}
+
+fn apply_resource_op(changes: &mut Vec<FileChange>, op: &ResourceOp) -> Result<()> {
+    match op {
+        ResourceOp::Create(create) => {
+            let overwrite = create.options.as_ref().and_then(|o| o.overwrite) == Some(true);
+            let ignore_existing = create.options.as_ref().and_then(|o| o.ignore_if_exists) == Some(true);
+            let change = entry(changes, &to_path(&create.uri))?;
+            if change.new.is_none() || overwrite {
+                change.new = Some(String::new());
+            } else if !ignore_existing {
+                anyhow::bail!("Cannot create {}: it already exists", change.path.display());
+            }
+        }
+        ResourceOp::Rename(rename) => {
+            let overwrite = rename.options.as_ref().and_then(|o| o.overwrite) == Some(true);
+            let ignore_existing = rename.options.as_ref().and_then(|o| o.ignore_if_exists) == Some(true);
+            let old_path = to_path(&rename.old_uri);
+            let content = entry(changes, &old_path)?
+                .new
+                .clone()
+                .with_context(|| format!("Cannot rename {}: it does not exist", old_path.display()))?;
+            let target = entry(changes, &to_path(&rename.new_uri))?;
+            if target.new.is_some() && !overwrite {
+                if ignore_existing {
+                    return Ok(());
+                }
+                anyhow::bail!("Cannot rename to {}: it already exists", target.path.display());
+            }
+            target.new = Some(content);
+            entry(changes, &old_path)?.new = None;
+        }
+        ResourceOp::Delete(delete) => {
+            let path = to_path(&delete.uri);
+            if path.is_dir() {
+                anyhow::bail!("Deleting directories is not supported: {}", path.display());
+            }
+            let ignore_missing = delete.options.as_ref().and_then(|o| o.ignore_if_not_exists) == Some(true);
+            let change = entry(changes, &path)?;
+            if change.new.is_none() && !ignore_missing {
+                anyhow::bail!("Cannot delete {}: it does not exist", path.display());
+            }
+            change.new = None;
+        }
+    }
+    Ok(())
+}
+
+/// The pending change for `path`, starting from what is on disk
+fn entry<'a>(changes: &'a mut Vec<FileChange>, path: &Path) -> Result<&'a mut FileChange> {
+    let index = match changes.iter().position(|c| c.path == path) {
+        Some(index) => index,
+        None => {
+            let old = match std::fs::read_to_string(path) {
+                Ok(text) => Some(text),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+            };
+            changes.push(FileChange {
+                path: path.to_path_buf(),
+                new: old.clone(),
+                old,
+            });
+            changes.len() - 1
+        }
+    };
+    Ok(&mut changes[index])
+}
+
+fn to_path(uri: &Uri) -> PathBuf {
+    PathBuf::from(uri_to_path_string(uri))
+}
+
+/// Apply `edits` to `text`. Ranges refer to the original text and must not
+/// overlap; edits inserting at the same position keep their given order.
+pub fn apply_text_edits(text: &str, edits: &[TextEdit]) -> Result<String> {
+    let mut spans: Vec<(usize, usize, &str)> = edits
+        .iter()
+        .map(|e| {
+            let start = text::position_to_offset(text, e.range.start);
+            let end = text::position_to_offset(text, e.range.end).max(start);
+            (start, end, e.new_text.as_str())
+        })
+        .collect();
+    // Stable, so equal starts stay in the order the server sent them
+    spans.sort_by_key(|(start, _, _)| *start);
+
+    let mut out = String::with_capacity(text.len());
+    let mut cursor = 0;
+    for (start, end, new_text) in spans {
+        if start < cursor {
+            anyhow::bail!("Server sent overlapping edits");
+        }
+        out.push_str(&text[cursor..start]);
+        out.push_str(new_text);
+        cursor = end;
+    }
+    out.push_str(&text[cursor..]);
+    Ok(out)
+}
+
+/// Write every change to disk, or none of them. New contents go to temporary
+/// files next to their targets first; only once all are written are they
+/// renamed into place. If a later step fails, the earlier ones are undone.
+pub fn apply(changes: &[FileChange]) -> Result<()> {
+    let mut staged: Vec<(PathBuf, &FileChange)> = Vec::new();
+    for change in changes {
+        let Some(new) = &change.new else {
+            continue;
+        };
+        match stage(&change.path, new) {
+            Ok(temp) => staged.push((temp, change)),
+            Err(e) => {
+                for (temp, _) in &staged {
+                    let _ = std::fs::remove_file(temp);
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let mut done: Vec<&FileChange> = Vec::new();
+    let mut result = Ok(());
+    for (temp, change) in &staged {
+        if let Err(e) = std::fs::rename(temp, &change.path) {
+            result = Err(e).with_context(|| format!("Failed to write {}", change.path.display()));
+            break;
+        }
+        done.push(change);
+    }
+    if result.is_ok() {
+        for change in changes.iter().filter(|c| c.new.is_none()) {
+            if let Err(e) = std::fs::remove_file(&change.path) {
+                result = Err(e).with_context(|| format!("Failed to delete {}", change.path.display()));
+                break;
+            }
+            done.push(change);
+        }
+    }
+
+    if let Err(e) = result {
+        for (temp, _) in &staged {
+            let _ = std::fs::remove_file(temp);
+        }
+        for change in done {
+            let _ = match &change.old {
+                Some(old) => std::fs::write(&change.path, old),
+                None => std::fs::remove_file(&change.path),
+            };
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Write `content` to a temporary file beside `path`, with the permissions
+/// `path` has now (e.g. `+x` on a script) so the rename doesn't drop them
+fn stage(path: &Path, content: &str) -> Result<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let temp = dir.join(format!(".{}.lsp-mcp-rs.tmp", name));
+    std::fs::write(&temp, content).with_context(|| format!("Failed to write {}", temp.display()))?;
+    if let Some(permissions) = permissions {
+        if let Err(e) = std::fs::set_permissions(&temp, permissions) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e).with_context(|| format!("Failed to set permissions of {}", temp.display()));
+        }
+    }
+    Ok(temp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, Range};
+
+    fn edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position { line: start.0, character: start.1 },
+                end: Position { line: end.0, character: end.1 },
+            },
+            new_text: new_text.into(),
+        }
+    }
+
+    /// A fresh, empty directory for one test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsp-mcp-rs-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn edits_apply_against_the_original_text_in_any_order() {
+        let text = "let a = 1;\nlet b = 2;\n";
+        let edits = [edit((1, 4), (1, 5), "bb"), edit((0, 4), (0, 5), "aa")];
+        assert_eq!(apply_text_edits(text, &edits).unwrap(), "let aa = 1;\nlet bb = 2;\n");
+    }
+
+    #[test]
+    fn inserts_at_one_position_keep_their_order() {
+        let edits = [edit((0, 1), (0, 1), "x"), edit((0, 1), (0, 1), "y"), edit((0, 0), (0, 1), "A")];
+        assert_eq!(apply_text_edits("ab", &edits).unwrap(), "Axyb");
+    }
+
+    #[test]
+    fn overlapping_edits_are_rejected() {
+        let edits = [edit((0, 0), (0, 3), "x"), edit((0, 2), (0, 4), "y")];
+        assert!(apply_text_edits("abcdef", &edits).is_err());
+    }
+
+    #[test]
+    fn edit_positions_are_utf16() {
+        let edits = [edit((0, 2), (0, 3), "B")];
+        assert_eq!(apply_text_edits("😀b", &edits).unwrap(), "😀B");
+    }
+
+    #[test]
+    fn apply_writes_creates_and_deletes() {
+        let dir = scratch_dir("apply");
+        std::fs::write(dir.join("changed"), "old").unwrap();
+        std::fs::write(dir.join("deleted"), "gone").unwrap();
+        let changes = [
+            FileChange { path: dir.join("changed"), old: Some("old".into()), new: Some("new".into()) },
+            FileChange { path: dir.join("deleted"), old: Some("gone".into()), new: None },
+            FileChange { path: dir.join("sub/created"), old: None, new: Some("fresh".into()) },
+        ];
+        apply(&changes).unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.join("changed")).unwrap(), "new");
+        assert!(!dir.join("deleted").exists());
+        assert_eq!(std::fs::read_to_string(dir.join("sub/created")).unwrap(), "fresh");
+        assert_eq!(file_names(&dir), ["changed", "sub"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_rolls_back_when_a_write_fails() {
+        let dir = scratch_dir("rollback");
+        std::fs::write(dir.join("first"), "old").unwrap();
+        std::fs::write(dir.join("deleted"), "kept").unwrap();
+        // A non-empty directory can't be replaced by a file, so this rename fails
+        std::fs::create_dir_all(dir.join("blocked/inner")).unwrap();
+        let changes = [
+            FileChange { path: dir.join("first"), old: Some("old".into()), new: Some("new".into()) },
+            FileChange { path: dir.join("created"), old: None, new: Some("fresh".into()) },
+            FileChange { path: dir.join("blocked"), old: Some(String::new()), new: Some("x".into()) },
+            FileChange { path: dir.join("deleted"), old: Some("kept".into()), new: None },
+        ];
+        assert!(apply(&changes).is_err());
+
+        assert_eq!(std::fs::read_to_string(dir.join("first")).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(dir.join("deleted")).unwrap(), "kept");
+        assert!(dir.join("blocked/inner").is_dir());
+        // Nothing created, no temporary files left behind
+        assert_eq!(file_names(&dir), ["blocked", "deleted", "first"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("permissions");
+        let script = dir.join("run.sh");
+        std::fs::write(&script, "echo old\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let changes = [FileChange { path: script.clone(), old: Some("echo old\n".into()), new: Some("echo new\n".into()) }];
+        apply(&changes).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&script).unwrap(), "echo new\n");
+        assert_eq!(std::fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,32 +4,58 @@
 
 mod client;
 mod config;
+mod diagnostics;
+mod diff;
+mod edit;
 mod protocol;
+mod server_log;
+mod text;
 
 use anyhow::Result;
-use lsp_types::{DocumentSymbolResponse, GotoDefinitionResponse, Hover, Location};
+use lsp_types::{
+    CallHierarchyItem, CodeActionKind, CodeActionOrCommand, CompletionItem, CompletionResponse,
+    CompletionTextEdit, Diagnostic, DiagnosticSeverity, DocumentHighlight, DocumentHighlightKind,
+    DocumentSymbolResponse, Documentation, FoldingRange, FoldingRangeKind, GotoDefinitionResponse,
+    Hover, InlayHint, InlayHintKind, InlayHintLabel, Location, NumberOrString, OneOf, Position,
+    Range, SelectionRange, SemanticToken, SemanticTokensLegend, SignatureHelp, SymbolKind,
+    TypeHierarchyItem, Uri, WorkspaceDiagnosticReport, WorkspaceDocumentDiagnosticReport,
+    WorkspaceEdit, WorkspaceSymbolResponse,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, Read, Write};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::AbortHandle;
 
-use client::LspClient;
+use client::{find_project_root, uri_to_path_string, LspClient};
 use config::{Config, ServerConfig};
 
 // ============================================================================
 // MCP Protocol Types
 // ============================================================================
 
+/// Any incoming message. Responses to our own requests (e.g. `roots/list`)
+/// have no method, only a result or an error.
 #[derive(Debug, Deserialize)]
 struct McpRequest {
+    #[allow(dead_code)]
     jsonrpc: String,
     id: Option<Value>,
+    #[serde(default)]
     method: String,
     #[serde(default)]
     params: Value,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -60,9 +86,21 @@ struct ToolDef {
 // LSP Manager
 // ============================================================================
 
+/// Server name and the project root an instance serves
+type ClientKey = (String, PathBuf);
+
 struct LspManager {
     config: Config,
-    clients: Mutex<HashMap<String, Arc<LspClient>>>,
+    clients: Mutex<HashMap<ClientKey, Arc<LspClient>>>,
+    /// Held while an instance is being spawned or restarted, so `clients`
+    /// needn't be: other servers and roots stay usable meanwhile
+    spawning: Mutex<HashMap<ClientKey, Arc<Mutex<()>>>>,
+    /// Recent restart times per instance, for backoff and the restart limit
+    restarts: Mutex<HashMap<ClientKey, Vec<Instant>>>,
+    /// Workspace roots from the MCP client, if it provides them. When set,
+    /// they are every instance's workspace folders and bound which files
+    /// tools may touch.
+    roots: std::sync::RwLock<Option<Vec<PathBuf>>>,
 }
 
 impl LspManager {
@@ -70,6 +108,70 @@ impl LspManager {
         Self {
             config,
             clients: Mutex::new(HashMap::new()),
+            spawning: Mutex::new(HashMap::new()),
+            restarts: Mutex::new(HashMap::new()),
+            roots: std::sync::RwLock::new(None),
+        }
+    }
+
+    fn roots(&self) -> Option<Vec<PathBuf>> {
+        self.roots.read().unwrap().clone()
+    }
+
+    /// Refuse paths outside the MCP client's roots, when it gave us any
+    fn check_in_roots(&self, path: &Path) -> Result<()> {
+        match self.roots() {
+            Some(roots) if innermost_root(&roots, path).is_none() => {}
+            _ => return Ok(()),
+        }
+        anyhow::bail!("{} is outside the workspace roots", path.display())
+    }
+
+    /// Adopt a new root list from the MCP client. Instances whose root is no
+    /// longer inside it are shut down; the rest are told about the change.
+    /// An empty list (or one with no `file://` roots) counts as no roots.
+    async fn set_roots(&self, roots: Vec<PathBuf>) {
+        if roots.is_empty() {
+            // Otherwise no instance and no file would be inside the boundary
+            *self.roots.write().unwrap() = None;
+            return;
+        }
+        let roots: Vec<PathBuf> = roots
+            .into_iter()
+            .map(|r| std::fs::canonicalize(&r).unwrap_or(r))
+            .collect();
+        *self.roots.write().unwrap() = Some(roots.clone());
+
+        let mut clients = self.clients.lock().await;
+        let mut dropped = Vec::new();
+        clients.retain(|(_, root), client| {
+            let keep = roots.iter().any(|r| root.starts_with(r));
+            if !keep {
+                dropped.push(client.clone());
+            }
+            keep
+        });
+        let remaining = distinct(clients.values());
+        drop(clients);
+
+        for client in distinct(dropped.iter()) {
+            if remaining.iter().any(|c| Arc::ptr_eq(c, &client)) {
+                continue;
+            }
+            eprintln!("[lsp-mcp-rs] Shutting down {} at {}: no longer a workspace root", client.name(), client.root().display());
+            tokio::spawn(async move {
+                let _ = client.shutdown().await;
+            });
+        }
+
+        for client in remaining {
+            // Servers without folder support keep what they were started with
+            if client.is_initialized().await && !client.supports_workspace_folders().await {
+                continue;
+            }
+            if let Err(e) = client.set_workspace_folders(&roots).await {
+                eprintln!("[lsp-mcp-rs] Failed to update workspace folders of {}: {}", client.name(), e);
+            }
         }
     }
 
@@ -81,41 +183,260 @@ impl LspManager {
             .map(|(name, cfg)| (name.to_string(), cfg.clone()))
     }
 
-    async fn get_client(&self, path: &Path) -> Result<Arc<LspClient>> {
+    /// The instance serving `path`: one per (server, project root). The root
+    /// comes from the server's `root_patterns` unless `root` overrides it.
+    async fn get_client(&self, path: &Path, root: Option<&Path>) -> Result<Arc<LspClient>> {
         let (name, config) = self
             .server_for_file(path)
             .ok_or_else(|| anyhow::anyhow!("No LSP configured for: {}", path.display()))?;
 
+        self.check_in_roots(path)?;
+        let roots = self.roots();
+
+        let root = match (root, &roots) {
+            (Some(root), _) if root.is_dir() => {
+                // Canonical, so one root spelled two ways is still one instance
+                let root = std::fs::canonicalize(root)?;
+                if innermost_root(std::slice::from_ref(&root), path).is_none() {
+                    anyhow::bail!("{} is not inside root {}", path.display(), root.display());
+                }
+                self.check_in_roots(&root)?;
+                root
+            }
+            (Some(root), _) => anyhow::bail!("Root is not a directory: {}", root.display()),
+            // The innermost MCP root holding the file; no guessing from markers
+            (None, Some(roots)) => innermost_root(roots, path)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} is outside the workspace roots", path.display()))?,
+            (None, None) => find_project_root(path, &config.root_patterns, config.root_policy).unwrap_or_else(|| {
+                path.parent()
+                    .unwrap_or(Path::new("/"))
+                    .to_path_buf()
+            }),
+        };
+        let key = (name.clone(), root.clone());
+
+        let clients = self.clients.lock().await;
+
+        if let Some(client) = clients.get(&key) {
+            if client.is_running().await {
+                client.touch();
+                return Ok(client.clone());
+            }
+        }
+
+        let candidates = if config.multi_root && !clients.contains_key(&key) {
+            distinct(clients.iter().filter(|(k, _)| k.0 == name).map(|(_, c)| c))
+        } else {
+            Vec::new()
+        };
+        // A dead instance may serve several roots; restarts of it share one lock
+        let spawn_key = match clients.get(&key) {
+            Some(dead) => (name.clone(), dead.root().to_path_buf()),
+            None => key.clone(),
+        };
+        drop(clients);
+
+        // A running instance that can take more workspace folders serves this
+        // root too, rather than spawning a duplicate server. Probed without
+        // `clients` held, since one may still be in its `initialize` handshake.
+        for client in candidates {
+            if client.is_running().await
+                && client.is_initialized().await
+                && client.supports_workspace_folders().await
+            {
+                client.add_workspace_folder(&root).await?;
+                client.touch();
+                // Another call may have settled this root in the meantime
+                return Ok(self.clients.lock().await.entry(key).or_insert(client).clone());
+            }
+        }
+
+        let spawning = self.spawning.lock().await.entry(spawn_key).or_default().clone();
+        let _spawning = spawning.lock().await;
+
+        // Whoever held the lock before us may have done the work already
+        let current = self.clients.lock().await.get(&key).cloned();
+        if let Some(client) = &current {
+            if client.is_running().await {
+                client.touch();
+                return Ok(client.clone());
+            }
+        }
+
+        if let Some(dead) = current {
+            let client = self.restart(config, &dead).await?;
+            // Every root the dead instance served moves to the new one
+            for value in self.clients.lock().await.values_mut() {
+                if Arc::ptr_eq(value, &dead) {
+                    *value = client.clone();
+                }
+            }
+            return Ok(client);
+        }
+
+        let max_instances = config.max_instances;
+        let client = Arc::new(LspClient::new(&name, config, root));
+        if let Some(roots) = &roots {
+            client.set_workspace_folders(roots).await?;
+        }
+        client.start().await?;
         let mut clients = self.clients.lock().await;
+        Self::evict_idle(&mut clients, &name, max_instances).await;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Make room for a new instance of `name` by shutting down the least
+    /// recently used idle ones (dead ones first). Busy instances are never
+    /// evicted, so the limit can be exceeded while they all are.
+    async fn evict_idle(clients: &mut HashMap<ClientKey, Arc<LspClient>>, name: &str, max_instances: usize) {
+        let instances = distinct(clients.iter().filter(|(k, _)| k.0 == name).map(|(_, c)| c));
 
-        if let Some(client) = clients.get(&name) {
-            return Ok(client.clone());
+        let mut candidates = Vec::new();
+        for client in &instances {
+            let running = client.is_running().await;
+            if !running || client.is_idle().await {
+                candidates.push((running, client.last_used(), client.clone()));
+            }
+        }
+        candidates.sort_by_key(|(running, last_used, _)| (*running, *last_used));
+
+        let excess = (instances.len() + 1).saturating_sub(max_instances.max(1));
+        for (_, _, client) in candidates.into_iter().take(excess) {
+            clients.retain(|_, c| !Arc::ptr_eq(c, &client));
+            eprintln!("[lsp-mcp-rs] Shutting down idle {} at {}", name, client.root().display());
+            tokio::spawn(async move {
+                let _ = client.shutdown().await;
+            });
         }
+    }
 
-        let client = Arc::new(LspClient::new(&name));
-        client.start(&config).await?;
-        clients.insert(name.clone(), client.clone());
+    /// Respawn a crashed server and bring it back to where the old one was:
+    /// same workspace folders, same open documents
+    async fn restart(&self, config: ServerConfig, dead: &LspClient) -> Result<Arc<LspClient>> {
+        let name = dead.name();
+        let key = (name.to_string(), dead.root().to_path_buf());
+        let window = Duration::from_secs(config.restart_window_secs);
+        let recent = {
+            let mut restarts = self.restarts.lock().await;
+            let history = restarts.entry(key).or_default();
+            history.retain(|t| t.elapsed() < window);
+            if history.len() >= config.max_restarts as usize {
+                anyhow::bail!(
+                    "{} crashed {} times in the last {}s; not restarting it again",
+                    name,
+                    history.len(),
+                    window.as_secs()
+                );
+            }
+            history.push(Instant::now());
+            history.len() as u32 - 1
+        };
+
+        let backoff = Duration::from_millis(config.restart_backoff_ms.saturating_mul(1 << recent.min(16)));
+        eprintln!("[lsp-mcp-rs] Restarting {} in {}ms", name, backoff.as_millis());
+        tokio::time::sleep(backoff).await;
+
+        let client = Arc::new(LspClient::with_log(name, config, dead.root().to_path_buf(), dead.stderr_log()));
+        client.start().await?;
+        client.set_workspace_folders(&dead.workspace_folders()).await?;
+        if dead.is_initialized().await {
+            client.ensure_initialized().await?;
+            for path in dead.open_documents().await {
+                if let Err(e) = client.open_file(&path).await {
+                    eprintln!("[lsp-mcp-rs] Failed to reopen {}: {}", path.display(), e);
+                }
+            }
+        }
         Ok(client)
     }
+
+    async fn running_clients(&self) -> Vec<Arc<LspClient>> {
+        let clients = distinct(self.clients.lock().await.values());
+        let mut running = Vec::new();
+        for client in clients {
+            if client.is_running().await {
+                running.push(client);
+            }
+        }
+        running
+    }
+
+    /// Every client for `name`, including ones that have died
+    async fn clients_named(&self, name: &str) -> Vec<Arc<LspClient>> {
+        distinct(self.clients.lock().await.values().filter(|c| c.name() == name))
+    }
+
+    async fn shutdown_all(&self) {
+        let clients = distinct(self.clients.lock().await.drain().map(|(_, c)| c).collect::<Vec<_>>().iter());
+        for client in clients {
+            if let Err(e) = client.shutdown().await {
+                eprintln!("[lsp-mcp-rs] Failed to shut down {}: {}", client.name(), e);
+            }
+        }
+    }
+}
+
+/// The innermost of `roots` containing `path`. Symlinks and `..` are
+/// resolved first so neither can step outside a root.
+fn innermost_root<'a>(roots: &'a [PathBuf], path: &Path) -> Option<&'a PathBuf> {
+    let resolved = match std::fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(_) if path.components().any(|c| c == std::path::Component::ParentDir) => return None,
+        Err(_) => path.to_path_buf(),
+    };
+    roots
+        .iter()
+        .filter(|r| resolved.starts_with(r))
+        .max_by_key(|r| r.components().count())
+}
+
+/// Several roots may map to the same instance; list each instance once
+fn distinct<'a>(clients: impl Iterator<Item = &'a Arc<LspClient>>) -> Vec<Arc<LspClient>> {
+    let mut unique: Vec<Arc<LspClient>> = Vec::new();
+    for client in clients {
+        if !unique.iter().any(|c| Arc::ptr_eq(c, client)) {
+            unique.push(client.clone());
+        }
+    }
+    unique
 }
 
 // ============================================================================
 // MCP Server
 // ============================================================================
 
+/// How long to wait for the MCP client to answer one of our requests
+const MCP_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct McpServer {
     manager: Arc<LspManager>,
+    /// Serialized responses, written to stdout by the writer task
+    outgoing: mpsc::UnboundedSender<String>,
+    /// Running `tools/call` tasks keyed by MCP request id, so they can be cancelled
+    in_flight: Mutex<HashMap<String, AbortHandle>>,
+    /// Requests we sent to the MCP client, keyed by id, awaiting its response
+    pending: Mutex<HashMap<String, oneshot::Sender<McpRequest>>>,
+    next_id: AtomicI64,
+    /// Whether the MCP client declared the `roots` capability
+    roots_supported: AtomicBool,
 }
 
 impl McpServer {
-    fn new(config: Config) -> Self {
+    fn new(config: Config, outgoing: mpsc::UnboundedSender<String>) -> Self {
         Self {
             manager: Arc::new(LspManager::new(config)),
+            outgoing,
+            in_flight: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicI64::new(1),
+            roots_supported: AtomicBool::new(false),
         }
     }
 
     fn get_tools() -> Vec<ToolDef> {
-        vec![
+        let mut tools = vec![
             ToolDef {
                 name: "lsp_hover".into(),
                 description: "Get hover information (documentation, type) at a position".into(),
@@ -129,6 +450,19 @@ impl McpServer {
                     "required": ["file", "line", "column"]
                 }),
             },
+            ToolDef {
+                name: "lsp_signature_help".into(),
+                description: "Get the signature of the call at a position, with the active parameter marked".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed), inside the call's parentheses" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
             ToolDef {
                 name: "lsp_definition".into(),
                 description: "Go to definition of symbol at position".into(),
@@ -142,6 +476,45 @@ impl McpServer {
                     "required": ["file", "line", "column"]
                 }),
             },
+            ToolDef {
+                name: "lsp_type_definition".into(),
+                description: "Go to the definition of the type of the symbol at position".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_implementation".into(),
+                description: "Find implementations of the trait, interface or method at position".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_declaration".into(),
+                description: "Go to the declaration of the symbol at position (e.g. the header for C/C++)".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
             ToolDef {
                 name: "lsp_references".into(),
                 description: "Find all references to symbol at position".into(),
@@ -155,6 +528,193 @@ impl McpServer {
                     "required": ["file", "line", "column"]
                 }),
             },
+            ToolDef {
+                name: "lsp_highlights".into(),
+                description: "Find the occurrences of the symbol at a position within its file, marked as reads or writes (e.g. every mutation of a variable)".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" },
+                        "kind": { "type": "string", "enum": ["read", "write", "text"], "description": "Only occurrences of this kind (default: all)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_selection_range".into(),
+                description: "List the ever larger syntactic ranges around a position (expression, statement, block, function...), innermost first".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_completion".into(),
+                description: "Get completions at a position, ranked as the server ranks them".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" },
+                        "prefix": { "type": "string", "description": "Only items starting with this (case-insensitive)" },
+                        "max_results": { "type": "integer", "description": "Maximum items to return (default: 20)" },
+                        "resolve": { "type": "integer", "description": "Fetch documentation for this many top items (default: 5)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_rename".into(),
+                description: "Rename the symbol at a position across the workspace. Returns a diff; pass apply to write it".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" },
+                        "new_name": { "type": "string", "description": "New name for the symbol" },
+                        "apply": { "type": "boolean", "description": "Write the changes to disk, all files or none (default: false, preview only)" }
+                    },
+                    "required": ["file", "line", "column", "new_name"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_code_actions".into(),
+                description: "List code actions (quick fixes, refactorings) available for a range".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Start line (0-indexed)" },
+                        "column": { "type": "integer", "description": "Start column (0-indexed)" },
+                        "end_line": { "type": "integer", "description": "End line (default: line)" },
+                        "end_column": { "type": "integer", "description": "End column (default: column)" },
+                        "kinds": { "type": "array", "items": { "type": "string" }, "description": "Only these kinds, e.g. [\"quickfix\"]" },
+                        "include_diagnostics": { "type": "boolean", "description": "Pass cached diagnostics in the range to the server, which most quick fixes need (default: true)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_apply_code_action".into(),
+                description: "Apply a code action from lsp_code_actions: writes its edit and runs its command. Takes the same arguments plus which action".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Start line (0-indexed)" },
+                        "column": { "type": "integer", "description": "Start column (0-indexed)" },
+                        "end_line": { "type": "integer", "description": "End line (default: line)" },
+                        "end_column": { "type": "integer", "description": "End column (default: column)" },
+                        "kinds": { "type": "array", "items": { "type": "string" }, "description": "Only these kinds, e.g. [\"quickfix\"]" },
+                        "include_diagnostics": { "type": "boolean", "description": "Pass cached diagnostics in the range to the server (default: true)" },
+                        "index": { "type": "integer", "description": "Number of the action in the lsp_code_actions list (1-based)" },
+                        "title": { "type": "string", "description": "Title of the action; used instead of index" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_format".into(),
+                description: "Format a whole file. Returns a diff; pass apply to write it".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "tab_size": { "type": "integer", "description": "Indent width (default: from config, 4)" },
+                        "insert_spaces": { "type": "boolean", "description": "Indent with spaces rather than tabs (default: from config, true)" },
+                        "apply": { "type": "boolean", "description": "Write the result to the file (default: false, preview only)" }
+                    },
+                    "required": ["file"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_format_range".into(),
+                description: "Format part of a file. Returns a diff; pass apply to write it".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Start line (0-indexed)" },
+                        "column": { "type": "integer", "description": "Start column (0-indexed, default: 0)" },
+                        "end_line": { "type": "integer", "description": "End line (0-indexed)" },
+                        "end_column": { "type": "integer", "description": "End column (0-indexed, default: end of line)" },
+                        "tab_size": { "type": "integer", "description": "Indent width (default: from config, 4)" },
+                        "insert_spaces": { "type": "boolean", "description": "Indent with spaces rather than tabs (default: from config, true)" },
+                        "apply": { "type": "boolean", "description": "Write the result to the file (default: false, preview only)" }
+                    },
+                    "required": ["file", "line", "end_line"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_call_hierarchy".into(),
+                description: "Show who calls the function at a position (incoming) or what it calls (outgoing), as a tree".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" },
+                        "direction": { "type": "string", "enum": ["incoming", "outgoing"], "description": "incoming: callers; outgoing: callees (default: incoming)" },
+                        "depth": { "type": "integer", "description": "Levels of calls to expand (default: 2)" },
+                        "max_nodes": { "type": "integer", "description": "Stop after this many entries (default: 100)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_type_hierarchy".into(),
+                description: "Show the supertypes or subtypes of the class, interface or trait at a position, as a tree".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "Line number (0-indexed)" },
+                        "column": { "type": "integer", "description": "Column number (0-indexed)" },
+                        "direction": { "type": "string", "enum": ["supertypes", "subtypes"], "description": "supertypes: what it extends/implements; subtypes: what extends/implements it (default: supertypes)" },
+                        "depth": { "type": "integer", "description": "Levels to expand (default: 3)" },
+                        "max_nodes": { "type": "integer", "description": "Stop after this many entries (default: 100)" }
+                    },
+                    "required": ["file", "line", "column"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_inlay_hints".into(),
+                description: "Get inlay hints (inferred types, parameter names) for a file or lines, optionally inlined into the source".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "First line (0-indexed, default: start of file)" },
+                        "end_line": { "type": "integer", "description": "Last line (0-indexed, default: end of file)" },
+                        "inline": { "type": "boolean", "description": "Return the source with hints inlined as comments, e.g. let x/*: Vec<String>*/ (default: false)" }
+                    },
+                    "required": ["file"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_semantic_tokens".into(),
+                description: "Classify the tokens of a file or lines (variable, macro, ...) with their modifiers (mutable, unsafe, ...) as the server's legend names them".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" },
+                        "line": { "type": "integer", "description": "First line (0-indexed, default: start of file)" },
+                        "end_line": { "type": "integer", "description": "Last line (0-indexed, default: end of file)" },
+                        "types": { "type": "array", "items": { "type": "string" }, "description": "Only tokens of these types, e.g. [\"macro\", \"variable\"]" },
+                        "modifiers": { "type": "array", "items": { "type": "string" }, "description": "Only tokens with all of these modifiers, e.g. [\"mutable\"]" }
+                    },
+                    "required": ["file"]
+                }),
+            },
             ToolDef {
                 name: "lsp_symbols".into(),
                 description: "List all symbols in a file".into(),
@@ -166,6 +726,31 @@ impl McpServer {
                     "required": ["file"]
                 }),
             },
+            ToolDef {
+                name: "lsp_folding_ranges".into(),
+                description: "List the foldable regions of a file (blocks, comments, imports) by line".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Absolute path to the file" }
+                    },
+                    "required": ["file"]
+                }),
+            },
+            ToolDef {
+                name: "lsp_workspace_symbols".into(),
+                description: "Search symbols by name across the whole workspace".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string", "description": "Symbol name or fragment, matched fuzzily by most servers" },
+                        "file": { "type": "string", "description": "Any file in the project; starts its LSP if needed" },
+                        "server": { "type": "string", "description": "Only query this server (default: all running servers)" },
+                        "limit": { "type": "integer", "description": "Maximum results per server (default: 50)" }
+                    },
+                    "required": ["query"]
+                }),
+            },
             ToolDef {
                 name: "lsp_diagnostics".into(),
                 description: "Get errors and warnings for a file".into(),
@@ -177,35 +762,177 @@ impl McpServer {
                     "required": ["file"]
                 }),
             },
+            ToolDef {
+                name: "lsp_workspace_diagnostics".into(),
+                description: "Get errors and warnings across the whole workspace. Repeated calls only report files whose diagnostics changed".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "file": { "type": "string", "description": "Any file in the project; starts its LSP if needed" },
+                        "server": { "type": "string", "description": "Only query this server (default: all running servers)" }
+                    }
+                }),
+            },
+            ToolDef {
+                name: "lsp_server_logs".into(),
+                description: "Get recent stderr output of a language server (useful when it fails to start)".into(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "server": { "type": "string", "description": "Server name from config" },
+                        "lines": { "type": "integer", "description": "Number of lines to return (default: 50)" }
+                    },
+                    "required": ["server"]
+                }),
+            },
             ToolDef {
                 name: "lsp_servers".into(),
                 description: "List configured LSP servers".into(),
                 input_schema: json!({ "type": "object", "properties": {} }),
             },
-        ]
+        ];
+
+        // Every tool that talks to a server accepts a per-call timeout, and
+        // every tool taking a file can override where its project root is
+        for tool in tools
+            .iter_mut()
+            .filter(|t| t.name != "lsp_servers" && t.name != "lsp_server_logs")
+        {
+            let properties = &mut tool.input_schema["properties"];
+            properties["timeout_ms"] = json!({
+                "type": "integer",
+                "description": "Timeout for each LSP request made by this call, in milliseconds (default: from config)"
+            });
+            if properties.get("file").is_some() {
+                properties["root"] = json!({
+                    "type": "string",
+                    "description": "Project root to start the LSP in (default: detected from root_patterns)"
+                });
+            }
+        }
+        tools
+    }
+
+    /// Handle one incoming message. Tool calls run on their own task so that
+    /// reading continues and a later `notifications/cancelled` can abort them.
+    async fn handle_message(self: &Arc<Self>, req: McpRequest) {
+        let Some(id) = req.id.clone() else {
+            match req.method.as_str() {
+                "notifications/cancelled" => self.cancel(&req.params["requestId"]).await,
+                "notifications/initialized" | "notifications/roots/list_changed"
+                    if self.roots_supported.load(Ordering::SeqCst) =>
+                {
+                    // The answer comes back through this read loop, so don't wait here
+                    let server = self.clone();
+                    tokio::spawn(async move { server.refresh_roots().await });
+                }
+                _ => {}
+            }
+            // Notifications never get a response
+            return;
+        };
+
+        if req.method.is_empty() {
+            if let Some(waiter) = self.pending.lock().await.remove(&id.to_string()) {
+                let _ = waiter.send(req);
+            }
+            return;
+        }
+
+        if req.method != "tools/call" {
+            let response = self.handle_request(req).await;
+            self.send(&response);
+            return;
+        }
+
+        let key = id.to_string();
+        let mut in_flight = self.in_flight.lock().await;
+        let server = self.clone();
+        let task_key = key.clone();
+        let task = tokio::spawn(async move {
+            let response = server.handle_request(req).await;
+            server.in_flight.lock().await.remove(&task_key);
+            server.send(&response);
+        });
+        in_flight.insert(key, task.abort_handle());
+    }
+
+    /// Abort a running tool call. Dropping its future drops any LSP request
+    /// it is waiting on, which sends `$/cancelRequest` to the server.
+    async fn cancel(&self, request_id: &Value) {
+        if let Some(task) = self.in_flight.lock().await.remove(&request_id.to_string()) {
+            eprintln!("[lsp-mcp-rs] Cancelling request {}", request_id);
+            task.abort();
+        }
+    }
+
+    /// Send a request to the MCP client and wait for its result
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(json!(id).to_string(), tx);
+
+        let msg = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let _ = self.outgoing.send(msg.to_string());
+
+        let reply = tokio::time::timeout(MCP_REQUEST_TIMEOUT, rx).await;
+        self.pending.lock().await.remove(&json!(id).to_string());
+        match reply {
+            Ok(Ok(McpRequest { error: Some(error), .. })) => anyhow::bail!("{} failed: {}", method, error),
+            Ok(Ok(reply)) => Ok(reply.result.unwrap_or(Value::Null)),
+            Ok(Err(_)) => anyhow::bail!("{} got no response", method),
+            Err(_) => anyhow::bail!("{} timed out", method),
+        }
+    }
+
+    /// Ask the MCP client for its roots and make them the workspace folders
+    async fn refresh_roots(&self) {
+        let result = match self.request("roots/list", json!({})).await {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("[lsp-mcp-rs] Failed to list roots: {}", e);
+                return;
+            }
+        };
+        let roots: Vec<PathBuf> = result["roots"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r["uri"].as_str()?.parse::<lsp_types::Uri>().ok())
+            .filter(|uri| uri.as_str().starts_with("file://"))
+            .map(|uri| PathBuf::from(uri_to_path_string(&uri)))
+            .collect();
+        eprintln!("[lsp-mcp-rs] Workspace roots: {:?}", roots);
+        self.manager.set_roots(roots).await;
+    }
+
+    fn send(&self, response: &McpResponse) {
+        match serde_json::to_string(response) {
+            Ok(msg) => {
+                let _ = self.outgoing.send(msg);
+            }
+            Err(e) => eprintln!("[lsp-mcp-rs] Failed to serialize response: {}", e),
+        }
     }
 
     async fn handle_request(&self, req: McpRequest) -> McpResponse {
         let id = req.id.unwrap_or(Value::Null);
 
         match req.method.as_str() {
-            "initialize" => McpResponse {
-                jsonrpc: "2.0".into(),
-                id,
-                result: Some(json!({
-                    "protocolVersion": "2024-11-05",
-                    "serverInfo": { "name": "lsp-mcp-rs", "version": env!("CARGO_PKG_VERSION") },
-                    "capabilities": { "tools": {} }
-                })),
-                error: None,
-            },
-
-            "notifications/initialized" => McpResponse {
-                jsonrpc: "2.0".into(),
-                id,
-                result: Some(json!({})),
-                error: None,
-            },
+            "initialize" => {
+                let roots = req.params["capabilities"].get("roots").is_some();
+                self.roots_supported.store(roots, Ordering::SeqCst);
+                McpResponse {
+                    jsonrpc: "2.0".into(),
+                    id,
+                    result: Some(json!({
+                        "protocolVersion": "2024-11-05",
+                        "serverInfo": { "name": "lsp-mcp-rs", "version": env!("CARGO_PKG_VERSION") },
+                        "capabilities": { "tools": {} }
+                    })),
+                    error: None,
+                }
+            }
 
             "tools/list" => McpResponse {
                 jsonrpc: "2.0".into(),
@@ -243,24 +970,54 @@ impl McpServer {
     }
 
     async fn call_tool(&self, name: &str, args: &Value) -> String {
+        let timeout = args["timeout_ms"].as_u64().map(Duration::from_millis);
+        client::with_timeout(timeout, self.dispatch_tool(name, args)).await
+    }
+
+    async fn dispatch_tool(&self, name: &str, args: &Value) -> String {
         match name {
             "lsp_hover" => self.tool_hover(args).await,
-            "lsp_definition" => self.tool_definition(args).await,
+            "lsp_signature_help" => self.tool_signature_help(args).await,
+            "lsp_definition" => self.tool_goto(args, "textDocument/definition", "No definition found").await,
+            "lsp_type_definition" => self.tool_goto(args, "textDocument/typeDefinition", "No type definition found").await,
+            "lsp_implementation" => self.tool_goto(args, "textDocument/implementation", "No implementations found").await,
+            "lsp_declaration" => self.tool_goto(args, "textDocument/declaration", "No declaration found").await,
             "lsp_references" => self.tool_references(args).await,
+            "lsp_highlights" => self.tool_highlights(args).await,
+            "lsp_selection_range" => self.tool_selection_range(args).await,
+            "lsp_completion" => self.tool_completion(args).await,
+            "lsp_rename" => self.tool_rename(args).await,
+            "lsp_code_actions" => self.tool_code_actions(args).await,
+            "lsp_apply_code_action" => self.tool_apply_code_action(args).await,
+            "lsp_format" => self.tool_format(args, false).await,
+            "lsp_format_range" => self.tool_format(args, true).await,
+            "lsp_call_hierarchy" => self.tool_call_hierarchy(args).await,
+            "lsp_type_hierarchy" => self.tool_type_hierarchy(args).await,
+            "lsp_inlay_hints" => self.tool_inlay_hints(args).await,
+            "lsp_semantic_tokens" => self.tool_semantic_tokens(args).await,
             "lsp_symbols" => self.tool_symbols(args).await,
+            "lsp_folding_ranges" => self.tool_folding_ranges(args).await,
+            "lsp_workspace_symbols" => self.tool_workspace_symbols(args).await,
             "lsp_diagnostics" => self.tool_diagnostics(args).await,
-            "lsp_servers" => self.tool_servers(),
+            "lsp_workspace_diagnostics" => self.tool_workspace_diagnostics(args).await,
+            "lsp_server_logs" => self.tool_server_logs(args).await,
+            "lsp_servers" => self.tool_servers().await,
             _ => format!("Error: Unknown tool: {}", name),
         }
     }
 
+    async fn client_for(&self, path: &Path, args: &Value) -> Result<Arc<LspClient>> {
+        let root = args["root"].as_str().map(Path::new);
+        self.manager.get_client(path, root).await
+    }
+
     async fn tool_hover(&self, args: &Value) -> String {
         let file = args["file"].as_str().unwrap_or_default();
         let line = args["line"].as_u64().unwrap_or(0) as u32;
         let col = args["column"].as_u64().unwrap_or(0) as u32;
 
         let path = Path::new(file);
-        match self.manager.get_client(path).await {
+        match self.client_for(path, args).await {
             Ok(client) => match client.hover(path, line, col).await {
                 Ok(Some(h)) => format_hover(h),
                 Ok(None) => "No hover information".into(),
@@ -270,43 +1027,573 @@ impl McpServer {
         }
     }
 
-    async fn tool_definition(&self, args: &Value) -> String {
-        let file = args["file"].as_str().unwrap_or_default();
-        let line = args["line"].as_u64().unwrap_or(0) as u32;
-        let col = args["column"].as_u64().unwrap_or(0) as u32;
+    async fn tool_signature_help(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+
+        let path = Path::new(file);
+        match self.client_for(path, args).await {
+            Ok(client) => match client.signature_help(path, line, col).await {
+                Ok(Some(h)) if !h.signatures.is_empty() => format_signature_help(h),
+                Ok(_) => "No signature help".into(),
+                Err(e) => format!("Error: {}", e),
+            },
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    /// Definition, type definition, implementation or declaration: `method`
+    /// is the LSP request, `empty` what to say when it finds nothing
+    async fn tool_goto(&self, args: &Value, method: &str, empty: &str) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+
+        let path = Path::new(file);
+        match self.client_for(path, args).await {
+            Ok(client) => match client.goto(method, path, line, col).await {
+                Ok(Some(d)) if !goto_is_empty(&d) => format_definition(d),
+                Ok(_) => empty.into(),
+                Err(e) => format!("Error: {}", e),
+            },
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    async fn tool_references(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+
+        let path = Path::new(file);
+        match self.client_for(path, args).await {
+            Ok(client) => match client.references(path, line, col).await {
+                Ok(Some(refs)) => format_references(refs),
+                Ok(None) => "No references found".into(),
+                Err(e) => format!("Error: {}", e),
+            },
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    async fn tool_highlights(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let kind = match args["kind"].as_str() {
+            None => None,
+            Some("read") => Some(DocumentHighlightKind::READ),
+            Some("write") => Some(DocumentHighlightKind::WRITE),
+            Some("text") => Some(DocumentHighlightKind::TEXT),
+            Some(other) => return format!("Error: Unknown kind '{}': expected read, write or text", other),
+        };
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let mut highlights = match client.document_highlights(path, line, col).await {
+            Ok(highlights) => highlights.unwrap_or_default(),
+            Err(e) => return format!("Error: {}", e),
+        };
+        // Servers that don't distinguish reads from writes leave the kind out, meaning text
+        highlights.retain(|h| kind.is_none_or(|k| h.kind.unwrap_or(DocumentHighlightKind::TEXT) == k));
+        if highlights.is_empty() {
+            return "No highlights".into();
+        }
+        let source = std::fs::read_to_string(path).unwrap_or_default();
+        format_highlights(&source, highlights)
+    }
+
+    async fn tool_selection_range(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let selection = match client.selection_ranges(path, vec![Position { line, character: col }]).await {
+            Ok(ranges) => ranges.unwrap_or_default().into_iter().next(),
+            Err(e) => return format!("Error: {}", e),
+        };
+        let Some(selection) = selection else {
+            return "No selection ranges at this position".into();
+        };
+        let source = std::fs::read_to_string(path).unwrap_or_default();
+        format_selection_range(&source, selection)
+    }
+
+    async fn tool_completion(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let prefix = args["prefix"].as_str().unwrap_or_default().to_lowercase();
+        let max_results = args["max_results"].as_u64().unwrap_or(20) as usize;
+        let resolve = args["resolve"].as_u64().unwrap_or(5) as usize;
+
+        let path = Path::new(file);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let (mut items, incomplete) = match client.completion(path, line, col).await {
+            Ok(Some(CompletionResponse::Array(items))) => (items, false),
+            Ok(Some(CompletionResponse::List(list))) => (list.items, list.is_incomplete),
+            Ok(None) => return "No completions".into(),
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        items.retain(|item| {
+            item.filter_text
+                .as_deref()
+                .unwrap_or(&item.label)
+                .to_lowercase()
+                .starts_with(&prefix)
+        });
+        let total = items.len();
+        items.sort_by(|a, b| {
+            let key = |i: &CompletionItem| (i.preselect != Some(true), i.sort_text.clone().unwrap_or_else(|| i.label.clone()));
+            key(a).cmp(&key(b))
+        });
+        items.truncate(max_results);
+
+        for item in items.iter_mut().take(resolve) {
+            // A failed resolve still leaves a usable item
+            if let Ok(resolved) = client.resolve_completion(item.clone()).await {
+                *item = resolved;
+            }
+        }
+
+        if items.is_empty() {
+            return "No completions".into();
+        }
+        format_completions(&items, total, incomplete)
+    }
+
+    async fn tool_rename(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let Some(new_name) = args["new_name"].as_str().filter(|n| !n.is_empty()) else {
+            return "Error: new_name is required".into();
+        };
+        let apply = args["apply"].as_bool().unwrap_or(false);
+
+        let path = Path::new(file);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        match client.prepare_rename(path, line, col).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return "Error: Nothing that can be renamed at this position".into(),
+            Err(e) => return format!("Error: {}", e),
+        }
+        let edit = match client.rename(path, line, col, new_name).await {
+            Ok(Some(edit)) => edit,
+            Ok(None) => return "No changes".into(),
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        match self.preview_or_apply(&edit, apply).await {
+            Ok(output) => output,
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    async fn tool_code_actions(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        match Self::code_actions(&client, path, args).await {
+            Ok(actions) if actions.is_empty() => "No code actions".into(),
+            Ok(actions) => format_code_actions(&actions),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    async fn tool_apply_code_action(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let actions = match Self::code_actions(&client, path, args).await {
+            Ok(actions) => actions,
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        // Actions are picked out of a fresh request, so the list may have
+        // moved on since lsp_code_actions; the title is the safer handle
+        let chosen = match (args["title"].as_str(), args["index"].as_u64()) {
+            (Some(title), _) => actions
+                .iter()
+                .find(|a| code_action_title(a) == title)
+                .or_else(|| actions.iter().find(|a| code_action_title(a).contains(title))),
+            (None, Some(index)) => (index as usize).checked_sub(1).and_then(|i| actions.get(i)),
+            (None, None) => return "Error: Pass index or title".into(),
+        };
+        let Some(chosen) = chosen.cloned() else {
+            return format!("Error: No such code action; available:\n{}", format_code_actions(&actions));
+        };
+
+        let (edit, command) = match chosen {
+            CodeActionOrCommand::Command(command) => (None, Some(command)),
+            CodeActionOrCommand::CodeAction(action) => {
+                let action = match client.resolve_code_action(action).await {
+                    Ok(action) => action,
+                    Err(e) => return format!("Error: {}", e),
+                };
+                if let Some(disabled) = &action.disabled {
+                    return format!("Error: {} is disabled: {}", action.title, disabled.reason);
+                }
+                (action.edit, action.command)
+            }
+        };
+
+        let mut output = Vec::new();
+        let (mut succeeded, mut failed) = (false, false);
+        let mut record = |step: Result<String>| match step {
+            Ok(text) => {
+                succeeded = true;
+                output.push(text);
+            }
+            Err(e) => {
+                failed = true;
+                output.push(format!("Error: {}", e));
+            }
+        };
+        if let Some(edit) = &edit {
+            record(self.preview_or_apply(edit, true).await);
+        }
+        if let Some(command) = &command {
+            // The edit is on disk and synced by now, so the command sees it.
+            // Edits the command asks for in turn are written as they come, so
+            // the server hears whether they landed.
+            let edits = std::sync::Mutex::new(Vec::new());
+            let result = client
+                .execute_command(command, |edit| {
+                    let edits = &edits;
+                    async move {
+                        let outcome = self.preview_or_apply(&edit, true).await;
+                        let reply = outcome.as_ref().map(|_| ()).map_err(|e| anyhow::anyhow!("{}", e));
+                        edits.lock().unwrap().push(outcome);
+                        reply
+                    }
+                })
+                .await;
+            match result {
+                Ok(()) => record(Ok(format!("Ran command {}", command.command))),
+                Err(e) => record(Err(anyhow::anyhow!("Command {} failed: {}", command.command, e))),
+            }
+            for outcome in edits.into_inner().unwrap() {
+                record(outcome);
+            }
+        }
+        if output.is_empty() {
+            return "Code action has neither an edit nor a command".into();
+        }
+        let output = output.join("\n");
+        // A failure in any step makes the whole call an error
+        if failed && succeeded {
+            return format!("Error: Code action only partly applied\n{}", output);
+        }
+        output
+    }
+
+    /// Code actions for the range in `args`, as lsp_code_actions lists them
+    async fn code_actions(client: &LspClient, path: &Path, args: &Value) -> Result<Vec<CodeActionOrCommand>> {
+        let start = Position {
+            line: args["line"].as_u64().unwrap_or(0) as u32,
+            character: args["column"].as_u64().unwrap_or(0) as u32,
+        };
+        let end = Position {
+            line: args["end_line"].as_u64().map_or(start.line, |l| l as u32),
+            character: args["end_column"].as_u64().map_or(start.character, |c| c as u32),
+        };
+        let range = Range { start, end };
+
+        let diagnostics = if args["include_diagnostics"].as_bool().unwrap_or(true) {
+            client.ensure_initialized().await?;
+            client.open_file(path).await?;
+            client
+                .cached_diagnostics(path)
+                .await?
+                .into_iter()
+                .filter(|d| d.range.start <= range.end && d.range.end >= range.start)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let only = args["kinds"].as_array().map(|kinds| {
+            kinds
+                .iter()
+                .filter_map(|k| k.as_str())
+                .map(|k| CodeActionKind::from(k.to_string()))
+                .collect()
+        });
+
+        Ok(client.code_actions(path, range, diagnostics, only).await?.unwrap_or_default())
+    }
+
+    async fn tool_format(&self, args: &Value, range: bool) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let apply = args["apply"].as_bool().unwrap_or(false);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        let mut options = client.formatting_options();
+        if let Some(tab_size) = args["tab_size"].as_u64() {
+            options.tab_size = tab_size as u32;
+        }
+        if let Some(insert_spaces) = args["insert_spaces"].as_bool() {
+            options.insert_spaces = insert_spaces;
+        }
+
+        let old = match std::fs::read_to_string(path) {
+            Ok(old) => old,
+            Err(e) => return format!("Error: Failed to read {}: {}", path.display(), e),
+        };
+
+        let edits = if range {
+            let end_line = args["end_line"].as_u64().unwrap_or(0) as u32;
+            let end_of_line = text::position_to_offset(&old, Position { line: end_line, character: u32::MAX });
+            let range = Range {
+                start: Position {
+                    line: args["line"].as_u64().unwrap_or(0) as u32,
+                    character: args["column"].as_u64().unwrap_or(0) as u32,
+                },
+                end: match args["end_column"].as_u64() {
+                    Some(column) => Position { line: end_line, character: column as u32 },
+                    None => text::offset_to_position(&old, end_of_line),
+                },
+            };
+            client.format_range(path, range, options).await
+        } else {
+            client.format(path, options).await
+        };
+        let edits = match edits {
+            Ok(Some(edits)) => edits,
+            Ok(None) => return "No changes".into(),
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        match edit::apply_text_edits(&old, &edits) {
+            Ok(new) if new == old => "Already formatted".into(),
+            Ok(new) => {
+                let change = edit::FileChange {
+                    path: path.to_path_buf(),
+                    old: Some(old),
+                    new: Some(new),
+                };
+                match self.preview_or_apply_changes(vec![change], apply).await {
+                    Ok(output) => output,
+                    Err(e) => format!("Error: {}", e),
+                }
+            }
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    /// Render `edit` as a diff, and with `apply` also write it to disk and
+    /// bring every server that has one of the files open up to date
+    async fn preview_or_apply(&self, edit: &WorkspaceEdit, apply: bool) -> Result<String> {
+        let changes = edit::plan(edit)?;
+        if changes.is_empty() {
+            return Ok("No changes".into());
+        }
+        self.preview_or_apply_changes(changes, apply).await
+    }
+
+    async fn preview_or_apply_changes(&self, changes: Vec<edit::FileChange>, apply: bool) -> Result<String> {
+        let diff: String = changes.iter().map(|c| c.diff()).collect();
+        let files = changes.len();
+
+        if !apply {
+            return Ok(format!("{} file(s) would change (pass apply: true to write them):\n{}", files, diff));
+        }
+        self.apply_changes(&changes).await?;
+        Ok(format!("Changed {} file(s):\n{}", files, diff))
+    }
+
+    async fn apply_changes(&self, changes: &[edit::FileChange]) -> Result<()> {
+        for change in changes {
+            self.manager.check_in_roots(&change.path)?;
+        }
+        edit::apply(changes)?;
+
+        for client in self.manager.running_clients().await {
+            for change in changes {
+                if let Err(e) = client.sync_file(&change.path).await {
+                    eprintln!("[lsp-mcp-rs] Failed to sync {} with {}: {}", change.path.display(), client.name(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn tool_call_hierarchy(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let outgoing = match args["direction"].as_str().unwrap_or("incoming") {
+            "incoming" => false,
+            "outgoing" => true,
+            other => return format!("Error: Unknown direction: {} (expected incoming or outgoing)", other),
+        };
+        let max_depth = args["depth"].as_u64().unwrap_or(2) as usize;
+        let max_nodes = args["max_nodes"].as_u64().unwrap_or(100) as usize;
+
+        let path = Path::new(file);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let roots = match client.prepare_call_hierarchy(path, line, col).await {
+            Ok(Some(items)) if !items.is_empty() => items,
+            Ok(_) => return "No call hierarchy at this position".into(),
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        let roots = roots.into_iter().map(|item| (format_hierarchy_item(&item, None), item));
+        let arrow = if outgoing { "-> " } else { "<- " };
+        let expand = |item: CallHierarchyItem| {
+            let client = client.clone();
+            async move {
+                let children = if outgoing {
+                    client
+                        .outgoing_calls(item.clone())
+                        .await?
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|c| (format_hierarchy_item(&c.to, Some((&item.uri, &c.from_ranges))), c.to))
+                        .collect()
+                } else {
+                    client
+                        .incoming_calls(item)
+                        .await?
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|c| (format_hierarchy_item(&c.from, Some((&c.from.uri, &c.from_ranges))), c.from))
+                        .collect()
+                };
+                Ok(children)
+            }
+        };
+        render_hierarchy(roots.collect(), expand, arrow, max_depth, max_nodes).await
+    }
+
+    async fn tool_type_hierarchy(&self, args: &Value) -> String {
+        let file = args["file"].as_str().unwrap_or_default();
+        let line = args["line"].as_u64().unwrap_or(0) as u32;
+        let col = args["column"].as_u64().unwrap_or(0) as u32;
+        let subtypes = match args["direction"].as_str().unwrap_or("supertypes") {
+            "supertypes" => false,
+            "subtypes" => true,
+            other => return format!("Error: Unknown direction: {} (expected supertypes or subtypes)", other),
+        };
+        let max_depth = args["depth"].as_u64().unwrap_or(3) as usize;
+        let max_nodes = args["max_nodes"].as_u64().unwrap_or(100) as usize;
+
+        let path = Path::new(file);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let roots = match client.prepare_type_hierarchy(path, line, col).await {
+            Ok(Some(items)) if !items.is_empty() => items,
+            Ok(_) => return "No type hierarchy at this position".into(),
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        let roots = roots.into_iter().map(|item| (format_hierarchy_item(&item, None), item));
+        let arrow = if subtypes { "v " } else { "^ " };
+        let expand = |item: TypeHierarchyItem| {
+            let client = client.clone();
+            async move {
+                let items = if subtypes {
+                    client.subtypes(item).await?
+                } else {
+                    client.supertypes(item).await?
+                };
+                Ok(items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|item| (format_hierarchy_item(&item, None), item))
+                    .collect())
+            }
+        };
+        render_hierarchy(roots.collect(), expand, arrow, max_depth, max_nodes).await
+    }
+
+    async fn tool_inlay_hints(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let inline = args["inline"].as_bool().unwrap_or(false);
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return format!("Error: Failed to read {}: {}", path.display(), e),
+        };
+
+        let range = line_range(&source, args);
+        let (first, last) = (range.start.line, range.end.line);
 
-        let path = Path::new(file);
-        match self.manager.get_client(path).await {
-            Ok(client) => match client.definition(path, line, col).await {
-                Ok(Some(d)) => format_definition(d),
-                Ok(None) => "No definition found".into(),
-                Err(e) => format!("Error: {}", e),
-            },
-            Err(e) => format!("Error: {}", e),
+        let hints = match client.inlay_hints(path, range).await {
+            Ok(hints) => hints.unwrap_or_default(),
+            Err(e) => return format!("Error: {}", e),
+        };
+        if inline {
+            return render_inlay_hints(&source, hints, first, last);
         }
+        if hints.is_empty() {
+            return "No inlay hints".into();
+        }
+        format_inlay_hints(hints)
     }
 
-    async fn tool_references(&self, args: &Value) -> String {
-        let file = args["file"].as_str().unwrap_or_default();
-        let line = args["line"].as_u64().unwrap_or(0) as u32;
-        let col = args["column"].as_u64().unwrap_or(0) as u32;
+    async fn tool_semantic_tokens(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return format!("Error: Failed to read {}: {}", path.display(), e),
+        };
+        let strings = |key: &str| -> Vec<String> {
+            args[key]
+                .as_array()
+                .map(|a| a.iter().filter_map(|v| v.as_str().map(String::from)).collect())
+                .unwrap_or_default()
+        };
+        let (types, modifiers) = (strings("types"), strings("modifiers"));
 
-        let path = Path::new(file);
-        match self.manager.get_client(path).await {
-            Ok(client) => match client.references(path, line, col).await {
-                Ok(Some(refs)) => format_references(refs),
-                Ok(None) => "No references found".into(),
-                Err(e) => format!("Error: {}", e),
-            },
-            Err(e) => format!("Error: {}", e),
-        }
+        let range = line_range(&source, args);
+        let lines = range.start.line..=range.end.line;
+        // Only ask for a range when one was given; the full request is the one every server has
+        let range = (args["line"].is_u64() || args["end_line"].is_u64()).then_some(range);
+
+        let (legend, tokens) = match client.semantic_tokens(path, range).await {
+            Ok(result) => result,
+            Err(e) => return format!("Error: {}", e),
+        };
+        format_semantic_tokens(&source, &legend, &tokens, lines, &types, &modifiers)
     }
 
     async fn tool_symbols(&self, args: &Value) -> String {
         let file = args["file"].as_str().unwrap_or_default();
 
         let path = Path::new(file);
-        match self.manager.get_client(path).await {
+        match self.client_for(path, args).await {
             Ok(client) => match client.document_symbols(path).await {
                 Ok(Some(s)) => format_symbols(s),
                 Ok(None) => "No symbols found".into(),
@@ -316,24 +1603,179 @@ impl McpServer {
         }
     }
 
+    async fn tool_folding_ranges(&self, args: &Value) -> String {
+        let path = Path::new(args["file"].as_str().unwrap_or_default());
+        let client = match self.client_for(path, args).await {
+            Ok(c) => c,
+            Err(e) => return format!("Error: {}", e),
+        };
+        match client.folding_ranges(path).await {
+            Ok(Some(ranges)) if !ranges.is_empty() => {
+                let source = std::fs::read_to_string(path).unwrap_or_default();
+                format_folding_ranges(&source, ranges)
+            }
+            Ok(_) => "No folding ranges".into(),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
     async fn tool_diagnostics(&self, args: &Value) -> String {
         let file = args["file"].as_str().unwrap_or_default();
 
         let path = Path::new(file);
-        match self.manager.get_client(path).await {
+        match self.client_for(path, args).await {
             Ok(client) => match client.diagnostics(path).await {
                 Ok(d) if d.is_empty() => "No diagnostics".into(),
-                Ok(d) => serde_json::to_string_pretty(&d).unwrap_or_default(),
+                Ok(d) => format_diagnostics(d),
                 Err(e) => format!("Error: {}", e),
             },
             Err(e) => format!("Error: {}", e),
         }
     }
 
-    fn tool_servers(&self) -> String {
+    /// Servers a workspace-wide tool queries: the one for `file` if given
+    /// (starting it if needed), otherwise every running one, narrowed to `server`
+    async fn workspace_clients(&self, args: &Value) -> Result<Vec<Arc<LspClient>>> {
+        let clients = if let Some(file) = args["file"].as_str() {
+            let client = self.client_for(Path::new(file), args).await?;
+            client.ensure_initialized().await?;
+            vec![client]
+        } else {
+            self.manager.running_clients().await
+        };
+
+        let clients: Vec<_> = match args["server"].as_str() {
+            Some(server) => clients.into_iter().filter(|c| c.name() == server).collect(),
+            None => clients,
+        };
+
+        if clients.is_empty() {
+            anyhow::bail!("No running LSP servers; pass a file to start one");
+        }
+        Ok(clients)
+    }
+
+    async fn tool_workspace_symbols(&self, args: &Value) -> String {
+        let query = args["query"].as_str().unwrap_or_default();
+        let limit = args["limit"].as_u64().unwrap_or(50) as usize;
+        let clients = match self.workspace_clients(args).await {
+            Ok(clients) => clients,
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        let single = clients.len() == 1;
+        let mut sections = Vec::new();
+        for client in clients {
+            let body = match Self::workspace_symbols(&client, query, limit).await {
+                Ok(symbols) if symbols.is_empty() => "No symbols found".into(),
+                Ok(symbols) => symbols.join("\n"),
+                Err(e) if single => return format!("Error: {}", e),
+                Err(e) => format!("{}", e),
+            };
+            if single {
+                return body;
+            }
+            sections.push(format!("[{}]\n{}", client.name(), body));
+        }
+        sections.join("\n\n")
+    }
+
+    /// Up to `limit` formatted matches from one server, resolving any it
+    /// located only by file
+    async fn workspace_symbols(client: &LspClient, query: &str, limit: usize) -> Result<Vec<String>> {
+        let symbols = match client.workspace_symbols(query).await? {
+            Some(WorkspaceSymbolResponse::Flat(symbols)) => symbols
+                .into_iter()
+                .take(limit)
+                .map(|s| format_symbol(&s.name, s.kind, s.container_name.as_deref(), &s.location.uri, Some(s.location.range)))
+                .collect(),
+            Some(WorkspaceSymbolResponse::Nested(symbols)) => {
+                let mut lines = Vec::new();
+                for symbol in symbols.into_iter().take(limit) {
+                    // Unresolved is still worth listing
+                    let symbol = match client.resolve_workspace_symbol(symbol.clone()).await {
+                        Ok(resolved) => resolved,
+                        Err(_) => symbol,
+                    };
+                    let (uri, range) = match &symbol.location {
+                        OneOf::Left(location) => (&location.uri, Some(location.range)),
+                        OneOf::Right(location) => (&location.uri, None),
+                    };
+                    lines.push(format_symbol(&symbol.name, symbol.kind, symbol.container_name.as_deref(), uri, range));
+                }
+                lines
+            }
+            None => Vec::new(),
+        };
+        Ok(symbols)
+    }
+
+    async fn tool_workspace_diagnostics(&self, args: &Value) -> String {
+        let clients = match self.workspace_clients(args).await {
+            Ok(clients) => clients,
+            Err(e) => return format!("Error: {}", e),
+        };
+
+        if let [client] = clients.as_slice() {
+            return match client.workspace_diagnostics().await {
+                Ok(report) => format_workspace_diagnostics(report),
+                Err(e) => format!("Error: {}", e),
+            };
+        }
+
+        let mut sections = Vec::new();
+        for client in clients {
+            let body = match client.workspace_diagnostics().await {
+                Ok(report) => format_workspace_diagnostics(report),
+                Err(e) => format!("{}", e),
+            };
+            sections.push(format!("[{}]\n{}", client.name(), body));
+        }
+        sections.join("\n\n")
+    }
+
+    async fn tool_server_logs(&self, args: &Value) -> String {
+        let server = args["server"].as_str().unwrap_or_default();
+        let lines = args["lines"].as_u64().unwrap_or(50) as usize;
+
+        if !self.manager.config.servers.contains_key(server) {
+            return format!("Error: Unknown server: {}", server);
+        }
+
+        let clients = self.manager.clients_named(server).await;
+        if clients.is_empty() {
+            return format!("{} has not been started yet", server);
+        }
+
+        let mut sections = Vec::new();
+        for client in clients {
+            let tail = client.stderr_tail(lines);
+            let status = if client.is_running().await { "running" } else { "not running" };
+            let body = if tail.is_empty() {
+                "(no stderr output)".to_string()
+            } else {
+                tail.join("\n")
+            };
+            sections.push(format!("[{} at {} - {}]\n{}", server, client.root().display(), status, body));
+        }
+        sections.join("\n\n")
+    }
+
+    async fn tool_servers(&self) -> String {
         let mut lines = vec!["Configured LSP servers:".to_string()];
         for (name, cfg) in &self.manager.config.servers {
             lines.push(format!("  {} -> {} ({})", name, cfg.command, cfg.extensions.join(", ")));
+            for client in self.manager.clients_named(name).await {
+                let status = if client.is_running().await { "running" } else { "not running" };
+                lines.push(format!("    {} at {}", status, client.root().display()));
+                for folder in client.workspace_folders() {
+                    lines.push(format!("      + {}", folder.display()));
+                }
+            }
+        }
+        if let Some(roots) = self.manager.roots() {
+            lines.push("Workspace roots (from the MCP client):".to_string());
+            lines.extend(roots.iter().map(|r| format!("  {}", r.display())));
         }
         lines.join("\n")
     }
@@ -361,6 +1803,85 @@ fn format_hover(h: Hover) -> String {
     }
 }
 
+fn format_signature_help(h: SignatureHelp) -> String {
+    let index = h.active_signature.unwrap_or(0) as usize;
+    let index = if index < h.signatures.len() { index } else { 0 };
+    let count = h.signatures.len();
+    let sig = &h.signatures[index];
+    let active = sig.active_parameter.or(h.active_parameter).map(|a| a as usize);
+
+    // Byte range of each parameter within the signature label
+    let mut search_from = 0;
+    let params: Vec<(Option<(usize, usize)>, &lsp_types::ParameterInformation)> = sig
+        .parameters
+        .iter()
+        .flatten()
+        .map(|p| {
+            let range = match &p.label {
+                lsp_types::ParameterLabel::Simple(s) => sig.label[search_from..].find(s.as_str()).map(|i| {
+                    let start = search_from + i;
+                    search_from = start + s.len();
+                    (start, start + s.len())
+                }),
+                lsp_types::ParameterLabel::LabelOffsets([start, end]) => {
+                    Some((text::utf16_to_byte(&sig.label, *start), text::utf16_to_byte(&sig.label, *end)))
+                        .filter(|(start, end)| start <= end)
+                }
+            };
+            (range, p)
+        })
+        .collect();
+
+    let mut label = sig.label.clone();
+    if let Some(Some((start, end))) = active.and_then(|a| params.get(a)).map(|(r, _)| *r) {
+        label = format!("{}**{}**{}", &sig.label[..start], &sig.label[start..end], &sig.label[end..]);
+    }
+
+    let mut lines = vec![label];
+    if let Some(doc) = &sig.documentation {
+        lines.push(String::new());
+        lines.push(documentation_text(doc).to_string());
+    }
+    if !params.is_empty() {
+        lines.push(String::new());
+        lines.push("Parameters:".into());
+        for (i, (range, p)) in params.iter().enumerate() {
+            let name = match (range, &p.label) {
+                (Some((start, end)), _) => &sig.label[*start..*end],
+                (None, lsp_types::ParameterLabel::Simple(s)) => s.as_str(),
+                (None, _) => "?",
+            };
+            let marker = if Some(i) == active { ">" } else { " " };
+            let doc = p
+                .documentation
+                .as_ref()
+                .map(|d| format!(" - {}", documentation_text(d).lines().next().unwrap_or_default()))
+                .unwrap_or_default();
+            lines.push(format!("{} {}{}", marker, name, doc));
+        }
+    }
+    if count > 1 {
+        lines.push(format!("(signature {} of {})", index + 1, count));
+    }
+    lines.join("\n")
+}
+
+fn documentation_text(doc: &Documentation) -> &str {
+    match doc {
+        Documentation::String(s) => s,
+        Documentation::MarkupContent(m) => &m.value,
+    }
+}
+
+/// Servers answer "nothing found" with an empty list as often as with null
+fn goto_is_empty(d: &GotoDefinitionResponse) -> bool {
+    match d {
+        GotoDefinitionResponse::Scalar(_) => false,
+        GotoDefinitionResponse::Array(a) => a.is_empty(),
+        GotoDefinitionResponse::Link(l) => l.is_empty(),
+    }
+}
+
 fn format_definition(d: GotoDefinitionResponse) -> String {
     let locs = match d {
         GotoDefinitionResponse::Scalar(l) => vec![l],
@@ -379,6 +1900,405 @@ fn format_definition(d: GotoDefinitionResponse) -> String {
         .join("\n")
 }
 
+fn format_completions(items: &[CompletionItem], total: usize, incomplete: bool) -> String {
+    let mut lines = Vec::new();
+    for item in items {
+        let kind = item.kind.map(|k| format!(" ({:?})", k)).unwrap_or_default();
+        let detail = item.detail.as_ref().map(|d| format!(" - {}", d)).unwrap_or_default();
+        lines.push(format!("{}{}{}", item.label, kind, detail));
+
+        let insert = match &item.text_edit {
+            Some(CompletionTextEdit::Edit(e)) => Some(&e.new_text),
+            Some(CompletionTextEdit::InsertAndReplace(e)) => Some(&e.new_text),
+            None => item.insert_text.as_ref(),
+        };
+        if let Some(insert) = insert.filter(|t| **t != item.label) {
+            lines.push(format!("  insert: {}", insert));
+        }
+
+        let docs = item.documentation.as_ref().map(documentation_text).unwrap_or_default();
+        // The summary is enough to pick an item; hover gives the rest
+        for doc_line in docs.lines().filter(|l| !l.trim().is_empty()).take(3) {
+            lines.push(format!("  | {}", doc_line));
+        }
+    }
+
+    if items.len() < total || incomplete {
+        lines.push(format!(
+            "Showing {} of {}{} items; narrow with prefix",
+            items.len(),
+            total,
+            if incomplete { "+" } else { "" }
+        ));
+    }
+    lines.join("\n")
+}
+
+fn code_action_title(action: &CodeActionOrCommand) -> &str {
+    match action {
+        CodeActionOrCommand::Command(c) => &c.title,
+        CodeActionOrCommand::CodeAction(a) => &a.title,
+    }
+}
+
+fn format_code_actions(actions: &[CodeActionOrCommand]) -> String {
+    actions
+        .iter()
+        .enumerate()
+        .map(|(i, action)| {
+            let mut line = format!("{}. {}", i + 1, code_action_title(action));
+            if let CodeActionOrCommand::CodeAction(a) = action {
+                if let Some(kind) = &a.kind {
+                    line.push_str(&format!(" [{}]", kind.as_str()));
+                }
+                if a.is_preferred == Some(true) {
+                    line.push_str(" (preferred)");
+                }
+                if let Some(disabled) = &a.disabled {
+                    line.push_str(&format!(" (disabled: {})", disabled.reason));
+                }
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Something in a call or type hierarchy; the two item types carry the same fields
+trait HierarchyItem: Clone {
+    fn name(&self) -> &str;
+    fn kind(&self) -> SymbolKind;
+    fn detail(&self) -> Option<&str>;
+    fn uri(&self) -> &Uri;
+    fn selection_start(&self) -> Position;
+
+    /// Where it is defined, to spot it again elsewhere in the tree
+    fn key(&self) -> (String, u32, u32) {
+        let start = self.selection_start();
+        (self.uri().as_str().to_string(), start.line, start.character)
+    }
+}
+
+macro_rules! impl_hierarchy_item {
+    ($t:ty) => {
+        impl HierarchyItem for $t {
+            fn name(&self) -> &str {
+                &self.name
+            }
+            fn kind(&self) -> SymbolKind {
+                self.kind
+            }
+            fn detail(&self) -> Option<&str> {
+                self.detail.as_deref()
+            }
+            fn uri(&self) -> &Uri {
+                &self.uri
+            }
+            fn selection_start(&self) -> Position {
+                self.selection_range.start
+            }
+        }
+    };
+}
+impl_hierarchy_item!(CallHierarchyItem);
+impl_hierarchy_item!(TypeHierarchyItem);
+
+/// An entry waiting to be printed (and maybe expanded)
+struct HierarchyNode<T> {
+    item: T,
+    label: String,
+    depth: usize,
+    /// Items on the path from the root, for spotting cycles
+    ancestors: Vec<(String, u32, u32)>,
+}
+
+/// Render a hierarchy as an indented tree, depth-first so each entry sits
+/// right under its parent. `expand` gives an item's children with their
+/// labels. Cycles and items already expanded elsewhere are marked rather
+/// than expanded again, and at most `max_nodes` entries are printed.
+async fn render_hierarchy<T, F, Fut>(
+    roots: Vec<(String, T)>,
+    expand: F,
+    arrow: &str,
+    max_depth: usize,
+    max_nodes: usize,
+) -> String
+where
+    T: HierarchyItem,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<Vec<(String, T)>>>,
+{
+    let mut stack: Vec<HierarchyNode<T>> = roots
+        .into_iter()
+        .rev()
+        .map(|(label, item)| HierarchyNode {
+            item,
+            label,
+            depth: 0,
+            ancestors: Vec::new(),
+        })
+        .collect();
+    let mut expanded = HashSet::new();
+    let mut lines = Vec::new();
+
+    while let Some(node) = stack.pop() {
+        if lines.len() >= max_nodes {
+            lines.push(format!("... stopped after {} entries (raise max_nodes or lower depth)", max_nodes));
+            break;
+        }
+        let indent = "  ".repeat(node.depth);
+        let key = node.item.key();
+        if node.ancestors.contains(&key) {
+            lines.push(format!("{}{} (recursive)", indent, node.label));
+            continue;
+        }
+        if expanded.contains(&key) && node.depth < max_depth {
+            lines.push(format!("{}{} (expanded above)", indent, node.label));
+            continue;
+        }
+        lines.push(format!("{}{}", indent, node.label));
+        if node.depth >= max_depth {
+            continue;
+        }
+        expanded.insert(key.clone());
+
+        let children = match expand(node.item).await {
+            Ok(children) => children,
+            Err(e) => {
+                lines.push(format!("{}  Error: {}", indent, e));
+                continue;
+            }
+        };
+        let mut ancestors = node.ancestors;
+        ancestors.push(key);
+        for (label, item) in children.into_iter().rev() {
+            stack.push(HierarchyNode {
+                item,
+                label: format!("{}{}", arrow, label),
+                depth: node.depth + 1,
+                ancestors: ancestors.clone(),
+            });
+        }
+    }
+    lines.join("\n")
+}
+
+/// `name (Kind) - file:line [detail]`, plus the lines of the calls if known
+fn format_hierarchy_item(item: &impl HierarchyItem, calls: Option<(&Uri, &[Range])>) -> String {
+    let line = item.selection_start().line + 1;
+    let mut label = format!("{} ({:?}) - {}:{}", item.name(), item.kind(), uri_to_path_string(item.uri()), line);
+    if let Some(detail) = item.detail().filter(|d| !d.is_empty()) {
+        label.push_str(&format!(" [{}]", detail));
+    }
+    if let Some((uri, ranges)) = calls.filter(|(_, r)| !r.is_empty()) {
+        let lines: Vec<String> = ranges.iter().map(|r| (r.start.line + 1).to_string()).collect();
+        label.push_str(&format!(", calls at {}:{}", uri_to_path_string(uri), lines.join(", ")));
+    }
+    label
+}
+
+/// Whole lines `line`..=`end_line` from `args`, clamped to `source`; the
+/// whole file by default
+fn line_range(source: &str, args: &Value) -> Range {
+    let last_line = source.lines().count().saturating_sub(1) as u32;
+    let first = args["line"].as_u64().map_or(0, |l| l as u32).min(last_line);
+    let last = args["end_line"].as_u64().map_or(last_line, |l| l as u32).clamp(first, last_line);
+    Range {
+        start: Position { line: first, character: 0 },
+        end: text::offset_to_position(source, text::position_to_offset(source, Position { line: last, character: u32::MAX })),
+    }
+}
+
+fn inlay_hint_label(hint: &InlayHint) -> String {
+    match &hint.label {
+        InlayHintLabel::String(s) => s.clone(),
+        InlayHintLabel::LabelParts(parts) => parts.iter().map(|p| p.value.as_str()).collect(),
+    }
+}
+
+fn format_inlay_hints(mut hints: Vec<InlayHint>) -> String {
+    hints.sort_by_key(|h| h.position);
+    hints
+        .iter()
+        .map(|h| {
+            let kind = match h.kind {
+                Some(InlayHintKind::TYPE) => " (type)",
+                Some(InlayHintKind::PARAMETER) => " (parameter)",
+                _ => "",
+            };
+            format!("{}:{}: {}{}", h.position.line + 1, h.position.character + 1, inlay_hint_label(h), kind)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lines `first..=last` of `source`, numbered, with each hint inserted as a
+/// comment where an editor would display it
+fn render_inlay_hints(source: &str, mut hints: Vec<InlayHint>, first: u32, last: u32) -> String {
+    let mut annotated = source.to_string();
+    // Back to front, so earlier offsets stay valid
+    hints.sort_by_key(|h| h.position);
+    for hint in hints.iter().rev() {
+        let offset = text::position_to_offset(source, hint.position);
+        // A label containing */ would end the comment early, and one with a
+        // newline would shift every line after it
+        let label = inlay_hint_label(hint).replace("*/", "* /").replace(['\r', '\n'], " ");
+        let comment = format!(
+            "{}/*{}*/{}",
+            if hint.padding_left == Some(true) { " " } else { "" },
+            label,
+            if hint.padding_right == Some(true) { " " } else { "" },
+        );
+        annotated.insert_str(offset, &comment);
+    }
+
+    let width = (last + 1).to_string().len();
+    annotated
+        .lines()
+        .enumerate()
+        .skip(first as usize)
+        .take((last - first + 1) as usize)
+        .map(|(i, line)| format!("{:>width$} | {}", i + 1, line, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decode `tokens` (each relative to the one before) into one line per token:
+/// its range, type, modifiers and source text, keeping those on `lines` that
+/// pass the filters
+fn format_semantic_tokens(
+    source: &str,
+    legend: &SemanticTokensLegend,
+    tokens: &[SemanticToken],
+    lines: RangeInclusive<u32>,
+    types: &[String],
+    modifiers: &[String],
+) -> String {
+    let source_lines: Vec<&str> = source.lines().collect();
+    let mut out = Vec::new();
+    let (mut line, mut start) = (0u32, 0u32);
+    for token in tokens {
+        if token.delta_line > 0 {
+            line += token.delta_line;
+            start = token.delta_start;
+        } else {
+            start += token.delta_start;
+        }
+        if !lines.contains(&line) {
+            continue;
+        }
+
+        let token_type = legend.token_types.get(token.token_type as usize).map_or("unknown", |t| t.as_str());
+        let token_modifiers: Vec<&str> = (0..u32::BITS as usize)
+            .filter(|bit| token.token_modifiers_bitset & (1 << bit) != 0)
+            .filter_map(|bit| legend.token_modifiers.get(bit).map(|m| m.as_str()))
+            .collect();
+        if !types.is_empty() && !types.iter().any(|t| t == token_type) {
+            continue;
+        }
+        if !modifiers.iter().all(|m| token_modifiers.contains(&m.as_str())) {
+            continue;
+        }
+
+        let line_text = source_lines.get(line as usize).copied().unwrap_or_default();
+        let from = text::utf16_to_byte(line_text, start);
+        let to = text::utf16_to_byte(line_text, start + token.length);
+        let token_modifiers = if token_modifiers.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", token_modifiers.join(", "))
+        };
+        out.push(format!(
+            "{}:{}-{}:{} {}{} {:?}",
+            line + 1,
+            start + 1,
+            line + 1,
+            start + token.length + 1,
+            token_type,
+            token_modifiers,
+            &line_text[from..to]
+        ));
+    }
+
+    if out.is_empty() {
+        return "No semantic tokens".into();
+    }
+    out.join("\n")
+}
+
+/// `range` as `line:col-line:col`, 1-indexed
+fn format_range(range: Range) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        range.start.line + 1,
+        range.start.character + 1,
+        range.end.line + 1,
+        range.end.character + 1
+    )
+}
+
+/// Line `line` of `source`, trimmed, for showing where something is
+fn source_line(source: &str, line: u32) -> &str {
+    source.lines().nth(line as usize).unwrap_or_default().trim()
+}
+
+fn format_highlights(source: &str, mut highlights: Vec<DocumentHighlight>) -> String {
+    highlights.sort_by_key(|h| h.range.start);
+    highlights
+        .iter()
+        .map(|h| {
+            let kind = match h.kind {
+                Some(DocumentHighlightKind::READ) => "read",
+                Some(DocumentHighlightKind::WRITE) => "write",
+                _ => "text",
+            };
+            format!("{} {}: {}", format_range(h.range), kind, source_line(source, h.range.start.line))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_folding_ranges(source: &str, mut ranges: Vec<FoldingRange>) -> String {
+    ranges.sort_by_key(|r| (r.start_line, std::cmp::Reverse(r.end_line)));
+    ranges
+        .iter()
+        .map(|r| {
+            let kind = match &r.kind {
+                Some(FoldingRangeKind::Comment) => " (comment)",
+                Some(FoldingRangeKind::Imports) => " (imports)",
+                Some(FoldingRangeKind::Region) => " (region)",
+                None => "",
+            };
+            let text = r.collapsed_text.as_deref().unwrap_or_else(|| source_line(source, r.start_line));
+            format!("{}-{}{}: {}", r.start_line + 1, r.end_line + 1, kind, text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// One line per level of `selection`, innermost first, with the text it
+/// covers (just its first line when it spans several)
+fn format_selection_range(source: &str, selection: SelectionRange) -> String {
+    let mut out = Vec::new();
+    let mut current = Some(Box::new(selection));
+    while let Some(selection) = current {
+        let range = selection.range;
+        let start = text::position_to_offset(source, range.start);
+        let end = text::position_to_offset(source, range.end).max(start);
+        let covered = &source[start..end];
+        let text = match covered.split_once('\n') {
+            Some((first, _)) => {
+                let lines = range.end.line.saturating_sub(range.start.line) + 1;
+                format!("{:?} ({} lines)", format!("{}...", first.trim_end()), lines)
+            }
+            None => format!("{:?}", covered),
+        };
+        out.push(format!("{}. {} {}", out.len() + 1, format_range(range), text));
+        current = selection.parent;
+    }
+    out.join("\n")
+}
+
 fn format_references(refs: Vec<Location>) -> String {
     if refs.is_empty() {
         return "No references found".into();
@@ -414,6 +2334,84 @@ fn format_symbols(s: DocumentSymbolResponse) -> String {
     }
 }
 
+fn format_symbol(name: &str, kind: SymbolKind, container: Option<&str>, uri: &Uri, range: Option<Range>) -> String {
+    let container = container.filter(|c| !c.is_empty()).map(|c| format!(" in {}", c)).unwrap_or_default();
+    let location = match range {
+        Some(r) => format!("{}:{}:{}", uri_to_path_string(uri), r.start.line + 1, r.start.character + 1),
+        None => uri_to_path_string(uri),
+    };
+    format!("{} ({:?}){} - {}", name, kind, container, location)
+}
+
+fn format_diagnostics(mut diags: Vec<Diagnostic>) -> String {
+    diags.sort_by_key(|d| (d.range.start.line, d.range.start.character));
+    diags
+        .iter()
+        .map(|d| {
+            let severity = match d.severity {
+                Some(DiagnosticSeverity::ERROR) => "error",
+                Some(DiagnosticSeverity::WARNING) => "warning",
+                Some(DiagnosticSeverity::INFORMATION) => "info",
+                Some(DiagnosticSeverity::HINT) => "hint",
+                _ => "diagnostic",
+            };
+            let code = match &d.code {
+                Some(NumberOrString::Number(n)) => format!("[{}]", n),
+                Some(NumberOrString::String(s)) => format!("[{}]", s),
+                None => String::new(),
+            };
+            let source = d.source.as_ref().map(|s| format!(" ({})", s)).unwrap_or_default();
+            let mut r = format!("{}:{}: {}{}{}: {}", d.range.start.line + 1, d.range.start.character + 1, severity, code, source, d.message);
+            for info in d.related_information.iter().flatten() {
+                let start = info.location.range.start;
+                r.push_str(&format!("\n    {}:{}:{}: {}", uri_to_path_string(&info.location.uri), start.line + 1, start.character + 1, info.message));
+            }
+            r
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_workspace_diagnostics(report: WorkspaceDiagnosticReport) -> String {
+    let mut changed = Vec::new();
+    let mut clean = Vec::new();
+    let mut unchanged = 0;
+
+    for item in report.items {
+        match item {
+            WorkspaceDocumentDiagnosticReport::Full(f) if f.full_document_diagnostic_report.items.is_empty() => {
+                clean.push(uri_to_path_string(&f.uri))
+            }
+            WorkspaceDocumentDiagnosticReport::Full(f) => {
+                changed.push((uri_to_path_string(&f.uri), f.full_document_diagnostic_report.items))
+            }
+            WorkspaceDocumentDiagnosticReport::Unchanged(_) => unchanged += 1,
+        }
+    }
+    changed.sort_by(|a, b| a.0.cmp(&b.0));
+    clean.sort();
+
+    let mut lines = Vec::new();
+    for (path, diags) in changed {
+        lines.push(path);
+        lines.extend(format_diagnostics(diags).lines().map(|l| format!("  {}", l)));
+    }
+    if !clean.is_empty() {
+        if clean.len() <= 20 {
+            lines.push(format!("No diagnostics: {}", clean.join(", ")));
+        } else {
+            lines.push(format!("No diagnostics in {} files", clean.len()));
+        }
+    }
+    if unchanged > 0 {
+        lines.push(format!("{} files unchanged since last call", unchanged));
+    }
+    if lines.is_empty() {
+        return "No diagnostics".into();
+    }
+    lines.join("\n")
+}
+
 // ============================================================================
 // Newline-delimited JSON I/O (Claude Desktop format)
 // ============================================================================
@@ -434,9 +2432,9 @@ async fn read_message_async() -> Option<String> {
                 let trimmed = line.trim();
                 if trimmed.is_empty() {
                     eprintln!("[lsp-mcp-rs] Empty line, skipping");
-                    None
+                    Some(String::new())
                 } else {
-                    eprintln!("[lsp-mcp-rs] Received: {}...", &trimmed[..trimmed.len().min(80)]);
+                    eprintln!("[lsp-mcp-rs] Received: {}...", log_prefix(trimmed));
                     Some(trimmed.to_string())
                 }
             }
@@ -451,17 +2449,26 @@ async fn read_message_async() -> Option<String> {
     .flatten()
 }
 
+/// The first 80 bytes of `msg` for logging, cut on a char boundary
+fn log_prefix(msg: &str) -> &str {
+    let mut end = msg.len().min(80);
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    &msg[..end]
+}
+
 /// Write a JSON line to stdout using spawn_blocking
 async fn write_message_async(msg: String) -> std::io::Result<()> {
     tokio::task::spawn_blocking(move || {
         let stdout = std::io::stdout();
         let mut stdout = stdout.lock();
-        eprintln!("[lsp-mcp-rs] Sending: {}...", &msg[..msg.len().min(80)]);
+        eprintln!("[lsp-mcp-rs] Sending: {}...", log_prefix(&msg));
         writeln!(stdout, "{}", msg)?;
         stdout.flush()
     })
     .await
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+    .map_err(std::io::Error::other)?
 }
 
 // ============================================================================
@@ -470,6 +2477,16 @@ async fn write_message_async(msg: String) -> std::io::Result<()> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // stdout carries the protocol, so logs go to stderr
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
     eprintln!("[lsp-mcp-rs] Starting...");
 
     let config = Config::load_default().unwrap_or_else(|e| {
@@ -477,7 +2494,17 @@ async fn main() -> Result<()> {
         Config { servers: HashMap::new() }
     });
 
-    let server = McpServer::new(config);
+    let (outgoing, mut responses) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(msg) = responses.recv().await {
+            if let Err(e) = write_message_async(msg).await {
+                eprintln!("[lsp-mcp-rs] Write error: {}", e);
+                break;
+            }
+        }
+    });
+
+    let server = Arc::new(McpServer::new(config, outgoing));
     eprintln!("[lsp-mcp-rs] Ready for JSONL messages");
 
     loop {
@@ -495,14 +2522,158 @@ async fn main() -> Result<()> {
             continue;
         };
 
-        let response = server.handle_request(req).await;
-        let response_str = serde_json::to_string(&response)?;
+        server.handle_message(req).await;
+    }
 
-        if let Err(e) = write_message_async(response_str).await {
-            eprintln!("[lsp-mcp-rs] Write error: {}", e);
-            break;
+    server.manager.shutdown_all().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> LspManager {
+        let config = toml::from_str(
+            r#"
+            [servers.rust]
+            command = "rust-analyzer"
+            extensions = [".rs"]
+            "#,
+        )
+        .unwrap();
+        LspManager::new(config)
+    }
+
+    #[tokio::test]
+    async fn empty_roots_keep_instances_and_allow_every_file() {
+        let manager = manager();
+        let config = manager.config.servers["rust"].clone();
+        let key = ("rust".to_string(), PathBuf::from("/project"));
+        let client = Arc::new(LspClient::new("rust", config, key.1.clone()));
+        manager.clients.lock().await.insert(key.clone(), client);
+
+        manager.set_roots(vec![PathBuf::from("/project")]).await;
+        assert!(manager.check_in_roots(Path::new("/elsewhere/main.rs")).is_err());
+
+        manager.set_roots(Vec::new()).await;
+        assert_eq!(manager.roots(), None);
+        assert!(manager.clients.lock().await.contains_key(&key));
+        assert!(manager.check_in_roots(Path::new("/elsewhere/main.rs")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn root_override_must_contain_the_file() {
+        let dir = std::env::temp_dir().join(format!("lsp-mcp-rs-{}-root-override", std::process::id()));
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+
+        let err = manager()
+            .get_client(&dir.join("a/main.rs"), Some(&dir.join("b/../b")))
+            .await
+            .err()
+            .unwrap();
+        let root = std::fs::canonicalize(dir.join("b")).unwrap();
+        assert!(err.to_string().ends_with(&format!("is not inside root {}", root.display())), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn function(name: &str, line: u32) -> CallHierarchyItem {
+        CallHierarchyItem {
+            name: name.into(),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            detail: None,
+            uri: "file:///project/main.rs".parse().unwrap(),
+            range: Range::default(),
+            selection_range: Range {
+                start: Position { line, character: 0 },
+                end: Position { line, character: 0 },
+            },
+            data: None,
         }
     }
 
-    Ok(())
+    /// `main` calls `parse` and `eval`; `eval` calls itself and `helper`,
+    /// which `parse` calls too
+    fn callees(item: CallHierarchyItem) -> impl Future<Output = Result<Vec<(String, CallHierarchyItem)>>> {
+        let callees: &[(&str, u32)] = match item.name.as_str() {
+            "main" => &[("parse", 10), ("eval", 20)],
+            "parse" => &[("helper", 30)],
+            "eval" => &[("eval", 20), ("helper", 30)],
+            _ => &[],
+        };
+        let children = callees.iter().map(|&(name, line)| (name.to_string(), function(name, line))).collect();
+        async move { Ok(children) }
+    }
+
+    async fn render_calls(max_depth: usize, max_nodes: usize) -> String {
+        let roots = vec![("main".to_string(), function("main", 0))];
+        render_hierarchy(roots, callees, "-> ", max_depth, max_nodes).await
+    }
+
+    #[tokio::test]
+    async fn hierarchy_marks_cycles_and_repeats() {
+        assert_eq!(
+            render_calls(5, 100).await,
+            "main\n  -> parse\n    -> helper\n  -> eval\n    -> eval (recursive)\n    -> helper (expanded above)"
+        );
+    }
+
+    #[tokio::test]
+    async fn hierarchy_respects_depth_and_node_budgets() {
+        assert_eq!(render_calls(1, 100).await, "main\n  -> parse\n  -> eval");
+        assert_eq!(
+            render_calls(5, 3).await,
+            "main\n  -> parse\n    -> helper\n... stopped after 3 entries (raise max_nodes or lower depth)"
+        );
+    }
+
+    #[test]
+    fn semantic_tokens_decode_relative_positions() {
+        use lsp_types::{SemanticTokenModifier, SemanticTokenType};
+
+        let source = "fn main() {\n    let x = foo(1);\n}\n";
+        let legend = SemanticTokensLegend {
+            token_types: vec![SemanticTokenType::FUNCTION, SemanticTokenType::VARIABLE, SemanticTokenType::NUMBER],
+            token_modifiers: vec![SemanticTokenModifier::DECLARATION, SemanticTokenModifier::READONLY],
+        };
+        let token = |delta_line, delta_start, length, token_type, token_modifiers_bitset| SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset,
+        };
+        // A new line restarts the column; tokens on the same line add to it
+        let tokens = [
+            token(0, 3, 4, 0, 1),
+            token(1, 8, 1, 1, 1),
+            token(0, 4, 3, 0, 0),
+            token(0, 4, 1, 2, 0),
+            token(1, 0, 1, 9, 0),
+        ];
+
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let cases = [
+            (
+                0..=2,
+                vec![],
+                vec![],
+                "1:4-1:8 function [declaration] \"main\"\n\
+                 2:9-2:10 variable [declaration] \"x\"\n\
+                 2:13-2:16 function \"foo\"\n\
+                 2:17-2:18 number \"1\"\n\
+                 3:1-3:2 unknown \"}\"",
+            ),
+            (1..=1, vec![], vec![], "2:9-2:10 variable [declaration] \"x\"\n2:13-2:16 function \"foo\"\n2:17-2:18 number \"1\""),
+            (0..=2, vec!["function"], vec![], "1:4-1:8 function [declaration] \"main\"\n2:13-2:16 function \"foo\""),
+            (0..=2, vec![], vec!["declaration"], "1:4-1:8 function [declaration] \"main\"\n2:9-2:10 variable [declaration] \"x\""),
+            (5..=6, vec![], vec![], "No semantic tokens"),
+        ];
+        for (lines, types, modifiers, expected) in cases {
+            let out = format_semantic_tokens(source, &legend, &tokens, lines.clone(), &strings(&types), &strings(&modifiers));
+            assert_eq!(out, expected, "lines {:?}, types {:?}, modifiers {:?}", lines, types, modifiers);
+        }
+    }
 }
@@ -28,6 +28,41 @@ pub struct JsonRpcError {
     pub data: Option<Value>,
 }
 
+/// Request id as sent by the server; JSON-RPC allows numbers or strings
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+}
+
+/// A request the server sends to us (e.g. `workspace/configuration`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerRequest {
+    pub jsonrpc: String,
+    pub id: RequestId,
+    pub method: String,
+    #[serde(default)]
+    pub params: Option<Value>,
+}
+
+/// Our reply to a `ServerRequest`. Unlike `JsonRpcResponse`, a successful
+/// reply always carries `result`, even when it is `null`.
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonRpcReply {
+    pub jsonrpc: String,
+    pub id: RequestId,
+    #[serde(flatten)]
+    pub outcome: ReplyOutcome,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyOutcome {
+    Result(Value),
+    Error(JsonRpcError),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JsonRpcNotification {
     pub jsonrpc: String,
@@ -47,6 +82,32 @@ impl JsonRpcRequest {
     }
 }
 
+impl JsonRpcError {
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    pub const INVALID_PARAMS: i32 = -32602;
+
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl JsonRpcReply {
+    pub fn new(id: RequestId, outcome: Result<Value, JsonRpcError>) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            outcome: match outcome {
+                Ok(result) => ReplyOutcome::Result(result),
+                Err(error) => ReplyOutcome::Error(error),
+            },
+        }
+    }
+}
+
 impl JsonRpcNotification {
     pub fn new(method: &str, params: Option<Value>) -> Self {
         Self {
@@ -61,3 +122,101 @@ pub fn encode_message(msg: &impl Serialize) -> String {
     let body = serde_json::to_string(msg).unwrap();
     format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
 }
+
+/// Any message read from the server, classified by shape
+#[derive(Debug)]
+pub enum InboundMessage {
+    /// Has `method` and `id`: the server expects a reply
+    Request(ServerRequest),
+    /// Has `id` but no `method`: a reply to one of our requests
+    Response(JsonRpcResponse),
+    /// Has `method` but no `id`
+    Notification(JsonRpcNotification),
+}
+
+pub fn classify_message(body: &[u8]) -> serde_json::Result<InboundMessage> {
+    let value: Value = serde_json::from_slice(body)?;
+    let has_method = value.get("method").is_some();
+    let has_id = value.get("id").is_some_and(|id| !id.is_null());
+
+    Ok(match (has_method, has_id) {
+        (true, true) => InboundMessage::Request(serde_json::from_value(value)?),
+        (true, false) => InboundMessage::Notification(serde_json::from_value(value)?),
+        (false, _) => InboundMessage::Response(serde_json::from_value(value)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn classify(msg: Value) -> InboundMessage {
+        classify_message(msg.to_string().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn responses_have_an_id_and_no_method() {
+        let msg = json!({ "jsonrpc": "2.0", "id": 7, "result": { "x": 1 } });
+        let InboundMessage::Response(response) = classify(msg) else {
+            panic!("not a response");
+        };
+        assert_eq!(response.id, Some(7));
+        assert_eq!(response.result, Some(json!({ "x": 1 })));
+
+        let msg = json!({ "jsonrpc": "2.0", "id": 8, "error": { "code": -32601, "message": "nope" } });
+        let InboundMessage::Response(response) = classify(msg) else {
+            panic!("not a response");
+        };
+        assert_eq!(response.error.map(|e| e.code), Some(JsonRpcError::METHOD_NOT_FOUND));
+    }
+
+    #[test]
+    fn notifications_have_a_method_and_no_id() {
+        let msg = json!({ "jsonrpc": "2.0", "method": "window/logMessage", "params": { "message": "hi" } });
+        let InboundMessage::Notification(notification) = classify(msg) else {
+            panic!("not a notification");
+        };
+        assert_eq!(notification.method, "window/logMessage");
+
+        // A null id is no id
+        let msg = json!({ "jsonrpc": "2.0", "id": null, "method": "$/progress" });
+        assert!(matches!(classify(msg), InboundMessage::Notification(_)));
+    }
+
+    #[test]
+    fn server_requests_keep_numeric_and_string_ids() {
+        let msg = json!({ "jsonrpc": "2.0", "id": 3, "method": "workspace/configuration", "params": { "items": [] } });
+        let InboundMessage::Request(request) = classify(msg) else {
+            panic!("not a request");
+        };
+        assert_eq!(request.id, RequestId::Number(3));
+        assert_eq!(request.method, "workspace/configuration");
+
+        let msg = json!({ "jsonrpc": "2.0", "id": "s1", "method": "workspace/workspaceFolders" });
+        let InboundMessage::Request(request) = classify(msg) else {
+            panic!("not a request");
+        };
+        assert_eq!(request.id, RequestId::String("s1".into()));
+        assert_eq!(request.params, None);
+    }
+
+    #[test]
+    fn replies_echo_the_id_as_sent() {
+        let reply = JsonRpcReply::new(RequestId::String("s1".into()), Ok(Value::Null));
+        assert_eq!(serde_json::to_value(reply).unwrap(), json!({ "jsonrpc": "2.0", "id": "s1", "result": null }));
+
+        let reply = JsonRpcReply::new(RequestId::Number(2), Err(JsonRpcError::new(JsonRpcError::METHOD_NOT_FOUND, "foo/bar")));
+        assert_eq!(
+            serde_json::to_value(reply).unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 2, "error": { "code": -32601, "message": "foo/bar" } })
+        );
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        assert!(classify_message(b"{not json").is_err());
+        // A request id must be a number or a string
+        assert!(classify_message(br#"{"jsonrpc": "2.0", "id": [1], "method": "x"}"#).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Bounded ring buffer of a language server's stderr output
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Longer lines are truncated; some servers dump whole JSON payloads to stderr
+const MAX_LINE_LEN: usize = 2000;
+
+pub struct ServerLog {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl ServerLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut line = line.trim_end().to_string();
+        if line.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+            line.push_str(" [...]");
+        }
+
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The last `n` lines, oldest first
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().skip(lines.len().saturating_sub(n)).cloned().collect()
+    }
+}
@@ -0,0 +1,156 @@
+//! Conversions between byte offsets and LSP positions.
+//!
+//! We don't negotiate a position encoding, so positions use the LSP default:
+//! `character` counts UTF-16 code units.
+
+use lsp_types::{Position, Range};
+
+/// LSP position of byte `offset` in `text`
+pub fn offset_to_position(text: &str, offset: usize) -> Position {
+    let offset = offset.min(text.len());
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Byte offset of LSP `position` in `text`. Positions past the end of a
+/// line clamp to its end, and past the last line to the end of `text`.
+pub fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..].find('\n').map_or(text.len(), |i| line_start + i);
+    line_start + utf16_to_byte(&text[line_start..line_end], position.character)
+}
+
+/// Byte offset of the `units`th UTF-16 code unit in `text`, clamped to its end
+pub fn utf16_to_byte(text: &str, units: u32) -> usize {
+    let mut count = 0;
+    for (i, c) in text.char_indices() {
+        if count >= units as usize {
+            return i;
+        }
+        count += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The single range in `old` that has to be replaced to get `new`, and its
+/// replacement text. Used for incremental `didChange`.
+pub fn changed_range(old: &str, new: &str) -> (Range, String) {
+    // Both strings agree up to `prefix`, so it is a char boundary in both
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(old.len().min(new.len()), |((i, _), _)| i);
+
+    let max_suffix = (old.len() - prefix).min(new.len() - prefix);
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let range = Range {
+        start: offset_to_position(old, prefix),
+        end: offset_to_position(old, old.len() - suffix),
+    };
+    (range, new[prefix..new.len() - suffix].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    /// `old` with `range` replaced by `text`
+    fn replace(old: &str, range: Range, text: &str) -> String {
+        let start = position_to_offset(old, range.start);
+        let end = position_to_offset(old, range.end);
+        format!("{}{}{}", &old[..start], text, &old[end..])
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        // The emoji is 4 bytes and 2 UTF-16 units (a surrogate pair)
+        let text = "a😀b\nc";
+        assert_eq!(position_to_offset(text, pos(0, 3)), 5);
+        assert_eq!(offset_to_position(text, 5), pos(0, 3));
+        assert_eq!(position_to_offset(text, pos(1, 0)), 7);
+        assert_eq!(offset_to_position(text, 7), pos(1, 0));
+        assert_eq!(offset_to_position(text, text.len()), pos(1, 1));
+    }
+
+    #[test]
+    fn positions_past_the_end_clamp() {
+        let text = "ab\ncd";
+        assert_eq!(position_to_offset(text, pos(0, 99)), 2);
+        assert_eq!(position_to_offset(text, pos(1, 99)), 5);
+        assert_eq!(position_to_offset(text, pos(9, 0)), 5);
+        assert_eq!(offset_to_position(text, 99), pos(1, 2));
+    }
+
+    #[test]
+    fn utf16_inside_a_surrogate_pair_rounds_up() {
+        assert_eq!(utf16_to_byte("😀x", 0), 0);
+        assert_eq!(utf16_to_byte("😀x", 1), 4);
+        assert_eq!(utf16_to_byte("😀x", 2), 4);
+        assert_eq!(utf16_to_byte("😀x", 3), 5);
+        assert_eq!(utf16_to_byte("😀x", 9), 5);
+    }
+
+    #[test]
+    fn changed_range_covers_only_the_difference() {
+        let (range, text) = changed_range("hello world", "hello there world");
+        assert_eq!(range, Range { start: pos(0, 6), end: pos(0, 6) });
+        assert_eq!(text, "there ");
+
+        let (range, text) = changed_range("a\nb\nc\n", "a\nc\n");
+        assert_eq!(replace("a\nb\nc\n", range, &text), "a\nc\n");
+        assert_eq!(range.start, pos(1, 0));
+    }
+
+    #[test]
+    fn changed_range_of_identical_text_is_empty() {
+        let (range, text) = changed_range("same", "same");
+        assert_eq!(range, Range { start: pos(0, 4), end: pos(0, 4) });
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn changed_range_splits_on_char_boundaries() {
+        // ä and Ĥ share their last byte, which must not count as common suffix
+        let (range, text) = changed_range("xäy", "xĤy");
+        assert_eq!(range, Range { start: pos(0, 1), end: pos(0, 2) });
+        assert_eq!(text, "Ĥ");
+
+        let (range, text) = changed_range("😀😀", "😀😁");
+        assert_eq!(range, Range { start: pos(0, 2), end: pos(0, 4) });
+        assert_eq!(text, "😁");
+    }
+
+    #[test]
+    fn changed_range_round_trips() {
+        let cases = [("", "abc"), ("abc", ""), ("aXb", "ab"), ("ab", "aXb"), ("x😀\ny", "x\n😀y"), ("aaa", "aa")];
+        for (old, new) in cases {
+            let (range, text) = changed_range(old, new);
+            assert_eq!(replace(old, range, &text), new, "{:?} -> {:?}", old, new);
+        }
+    }
+}